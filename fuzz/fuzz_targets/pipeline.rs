@@ -0,0 +1,34 @@
+//! Drive arbitrary source and input through the lexer, parser and
+//! interpreter. Errors are fine, panics are not.
+//!
+//! The data is the source, optionally followed by a NUL byte and the input
+//! read by `,`.
+
+#![no_main]
+
+use brainfreeze::{interpreter::Interpreter, lexer::Lexer, parser::Parser};
+use libfuzzer_sys::fuzz_target;
+
+/// Programs that never halt are cut off instead of timing out
+const MAX_STEPS: u64 = 100_000;
+
+fuzz_target!(|data: &[u8]| {
+    let (source, input) = match data.iter().position(|&byte| byte == 0) {
+        Some(index) => (&data[..index], &data[index + 1..]),
+        None => (data, &[][..]),
+    };
+    let source = String::from_utf8_lossy(source).into_owned();
+
+    let Ok(tokens) = Lexer::new(source).scan_tokens() else {
+        return;
+    };
+    let Ok(mut program) = Parser::new(tokens).parse() else {
+        return;
+    };
+
+    let mut interpreter = Interpreter::builder()
+        .input(input.to_vec())
+        .max_steps(MAX_STEPS)
+        .build();
+    let _ = interpreter.interpret(&mut program);
+});
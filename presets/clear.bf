@@ -0,0 +1,5 @@
+# Clears a cell with [-] about 65 thousand times, then prints "Done"
+# Each clear walks a cell down from 255 unless the [-] idiom is optimized
+
+-[>-[>-[-]<-]<-]
+++++++++[>++++++++<-]>++++.+++++++++++++++++++++++++++++++++++++++++++.-.---------.[-]++++++++++.
@@ -0,0 +1,193 @@
+//! # Binary Programs
+//! The `.bfc` format stores a program without an image to carry it: the
+//! format header followed by the token stream, one byte per token.
+//!
+//! ```plaintext
+//! header : same bytes as in images, see `header`
+//! tokens : (kind: u8, count: varint if kind is + - > <)*
+//! ```
+//!
+//! Counts are LEB128 varints, so runs of any length take a few bytes.
+//! Compressed programs store the compressed source instead of tokens,
+//! prefixed by its length as a u32 (little endian).
+
+use std::fmt::Display;
+
+use crate::{
+    compress,
+    header::{Header, HeaderError, MAGIC, PREAMBLE_LENGTH},
+    lexer::{self, Lexer, Position, Token, TokenKind},
+};
+
+pub const EXTENSION: &str = ".bfc";
+
+const KIND_RIGHT: u8 = 0;
+const KIND_LEFT: u8 = 1;
+const KIND_INCREMENT: u8 = 2;
+const KIND_DECREMENT: u8 = 3;
+const KIND_LOOP_START: u8 = 4;
+const KIND_LOOP_END: u8 = 5;
+const KIND_PUT_CHAR: u8 = 6;
+const KIND_READ_CHAR: u8 = 7;
+const KIND_EOF: u8 = 8;
+
+#[derive(Debug)]
+pub enum BinaryError {
+    FileNotFound,
+    SaveFailed,
+    NotAProgram,
+    InvalidHeader(HeaderError),
+    CorruptProgram,
+}
+
+impl Display for BinaryError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BinaryError::FileNotFound => formatter.write_str("file not found"),
+            BinaryError::SaveFailed => formatter.write_str("failed to save file"),
+            BinaryError::NotAProgram => formatter.write_str("not a brainfreeze program"),
+            BinaryError::InvalidHeader(e) => {
+                formatter.write_fmt(format_args!("invalid header, {}", e))
+            }
+            BinaryError::CorruptProgram => formatter.write_str("program is corrupt"),
+        }
+    }
+}
+
+pub(crate) fn push_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+pub(crate) fn read_varint(bytes: &mut &[u8]) -> Option<usize> {
+    let mut value: usize = 0;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Encode the header and the tokens into the bytes of a `.bfc` file.
+/// Compression is dropped when it doesn't make the program smaller.
+pub fn encode(tokens: &[Token], header: &Header) -> Result<Vec<u8>, BinaryError> {
+    let program = encode_tokens(tokens);
+
+    if header.compressed {
+        let data = compress::compress(
+            lexer::render(tokens, &[], lexer::DEFAULT_COMMENT_CHARS[0]).as_bytes(),
+        );
+        if 4 + data.len() < program.len() {
+            let mut bytes = header.encode().map_err(BinaryError::InvalidHeader)?;
+            let length = u32::try_from(data.len()).map_err(|_| BinaryError::CorruptProgram)?;
+            bytes.extend_from_slice(&length.to_le_bytes());
+            bytes.extend(data);
+            return Ok(bytes);
+        }
+    }
+
+    let header = Header {
+        compressed: false,
+        ..header.clone()
+    };
+    let mut bytes = header.encode().map_err(BinaryError::InvalidHeader)?;
+    bytes.extend(program);
+    Ok(bytes)
+}
+
+fn encode_tokens(tokens: &[Token]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    for token in tokens {
+        let (kind, count) = match token.kind() {
+            TokenKind::Right(count) => (KIND_RIGHT, Some(*count)),
+            TokenKind::Left(count) => (KIND_LEFT, Some(*count)),
+            TokenKind::Increment(count) => (KIND_INCREMENT, Some(*count)),
+            TokenKind::Decrement(count) => (KIND_DECREMENT, Some(*count)),
+            TokenKind::LoopStart => (KIND_LOOP_START, None),
+            TokenKind::LoopEnd => (KIND_LOOP_END, None),
+            TokenKind::PutChar => (KIND_PUT_CHAR, None),
+            TokenKind::ReadChar => (KIND_READ_CHAR, None),
+            TokenKind::EOF => (KIND_EOF, None),
+        };
+        bytes.push(kind);
+        if let Some(count) = count {
+            push_varint(&mut bytes, count);
+        }
+    }
+
+    bytes
+}
+
+/// Decode the bytes of a `.bfc` file. Tokens are positioned by their index
+/// since there is no source or image to point into.
+pub fn decode(bytes: &[u8]) -> Result<(Header, Vec<Token>), BinaryError> {
+    let preamble = bytes
+        .get(..PREAMBLE_LENGTH)
+        .ok_or(BinaryError::NotAProgram)?;
+    if &preamble[..MAGIC.len()] != MAGIC {
+        return Err(BinaryError::NotAProgram);
+    }
+
+    let length = u16::from_le_bytes([preamble[4], preamble[5]]) as usize;
+    let fields = bytes
+        .get(PREAMBLE_LENGTH..PREAMBLE_LENGTH + length)
+        .ok_or(BinaryError::InvalidHeader(HeaderError::Truncated))?;
+    let header = Header::decode_fields(preamble[3], fields).map_err(BinaryError::InvalidHeader)?;
+    let mut rest = &bytes[PREAMBLE_LENGTH + length..];
+
+    if header.compressed {
+        let length = rest.get(..4).ok_or(BinaryError::CorruptProgram)?;
+        let length = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        let data = rest.get(4..4 + length).ok_or(BinaryError::CorruptProgram)?;
+        let source = compress::decompress(data).ok_or(BinaryError::CorruptProgram)?;
+        let source = String::from_utf8(source).map_err(|_| BinaryError::CorruptProgram)?;
+        let tokens = Lexer::new(source)
+            .scan_tokens()
+            .map_err(|_| BinaryError::CorruptProgram)?;
+        return Ok((header, tokens));
+    }
+
+    let mut tokens = Vec::new();
+    while let Some((&kind, remaining)) = rest.split_first() {
+        rest = remaining;
+        let kind = match kind {
+            KIND_RIGHT..=KIND_DECREMENT => {
+                let count = read_varint(&mut rest).ok_or(BinaryError::CorruptProgram)?;
+                match kind {
+                    KIND_RIGHT => TokenKind::Right(count),
+                    KIND_LEFT => TokenKind::Left(count),
+                    KIND_INCREMENT => TokenKind::Increment(count),
+                    _ => TokenKind::Decrement(count),
+                }
+            }
+            KIND_LOOP_START => TokenKind::LoopStart,
+            KIND_LOOP_END => TokenKind::LoopEnd,
+            KIND_PUT_CHAR => TokenKind::PutChar,
+            KIND_READ_CHAR => TokenKind::ReadChar,
+            KIND_EOF => TokenKind::EOF,
+            _ => return Err(BinaryError::CorruptProgram),
+        };
+        let position = Position::new(0, tokens.len() as u32);
+        tokens.push(Token::new(kind, position));
+    }
+
+    Ok((header, tokens))
+}
+
+pub fn write(path: &str, tokens: &[Token], header: &Header) -> Result<(), BinaryError> {
+    let bytes = encode(tokens, header)?;
+    std::fs::write(path, bytes).map_err(|_| BinaryError::SaveFailed)
+}
+
+pub fn read(path: &str) -> Result<(Header, Vec<Token>), BinaryError> {
+    let bytes = std::fs::read(path).map_err(|_| BinaryError::FileNotFound)?;
+    decode(&bytes)
+}
@@ -0,0 +1,232 @@
+//! # Bytecode
+//! Lowers the syntax tree into a flat list of instructions. Loops become a
+//! pair of jumps whose targets are resolved at compile time, so the VM in
+//! [`Interpreter::run_bytecode`](crate::interpreter::Interpreter::run_bytecode)
+//! runs it with a single program counter instead of walking the tree.
+//!
+//! Depending on the [`OptLevel`], lowering also replaces common loops with
+//! direct arithmetic: `[-]` and `[+]` zero the cell in one instruction and
+//! multiply loops such as `[->++<]` add a multiple of the cell to their
+//! neighbours. Steps and loop counts are still reported as if the loops
+//! ran, but the pointer history and memory logger only see the result.
+
+use crate::{
+    interpreter::{self, Interpret, RuntimeError, State},
+    lexer::Position,
+    parser::{Node, Operator, Program},
+    stats::Profile,
+};
+
+/// Which loops lowering replaces with arithmetic
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum OptLevel {
+    /// Run every loop as written, for debugging
+    #[value(alias = "0")]
+    None,
+    /// Zero the cell directly for `[-]` and `[+]`
+    #[default]
+    #[value(alias = "1")]
+    Clear,
+    /// Also collapse multiply and copy loops like `[->++<]`
+    #[value(alias = "2")]
+    Arithmetic,
+}
+
+/// Add `factor` times the current cell to the cell at `offset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddMul {
+    pub offset: isize,
+    pub factor: u8,
+}
+
+/// A loop that only adds multiples of its cell to cells around it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Multiply {
+    pub targets: Vec<AddMul>,
+    /// Lowest and highest offset the body reaches, pointer included
+    pub reach: (isize, isize),
+    /// Commands in the loop body, what a cycle costs in steps
+    pub body_length: u64,
+}
+
+impl Multiply {
+    /// Multiply loop for a body that doesn't move the pointer overall and
+    /// decrements its cell by one per cycle, `None` for any other body
+    fn detect(program: &Program) -> Option<Self> {
+        let mut deltas: Vec<(isize, u8)> = Vec::new();
+        let mut offset: isize = 0;
+        let mut reach = (0, 0);
+
+        for node in program.commands.iter() {
+            match node {
+                Node::Op(Operator::Right(v)) => offset = offset.checked_add_unsigned(*v)?,
+                Node::Op(Operator::Left(v)) => offset = offset.checked_sub_unsigned(*v)?,
+                Node::Op(operator @ (Operator::Increment(v) | Operator::Decrement(v))) => {
+                    let v = *v as u8;
+                    let delta = match operator {
+                        Operator::Increment(_) => v,
+                        _ => 0u8.wrapping_sub(v),
+                    };
+                    match deltas.iter_mut().find(|(o, _)| *o == offset) {
+                        Some((_, total)) => *total = total.wrapping_add(delta),
+                        None => deltas.push((offset, delta)),
+                    }
+                }
+                _ => return None,
+            }
+            reach = (reach.0.min(offset), reach.1.max(offset));
+        }
+
+        let decrement = deltas
+            .iter()
+            .find(|(o, _)| *o == 0)
+            .map(|(_, delta)| *delta);
+        if offset != 0 || decrement != Some(u8::MAX) {
+            return None;
+        }
+
+        let targets = deltas
+            .into_iter()
+            .filter(|&(offset, factor)| offset != 0 && factor != 0)
+            .map(|(offset, factor)| AddMul { offset, factor })
+            .collect();
+        Some(Self {
+            targets,
+            reach,
+            body_length: program.commands.len() as u64,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Operator(Operator),
+    /// Jump past the matching `LoopEnd` at `end` when the cell is zero.
+    /// `index` is the loop's entry in the iteration counts.
+    LoopStart {
+        end: usize,
+        index: usize,
+    },
+    /// Jump back to the body of the loop starting at `start` when the cell
+    /// is not zero
+    LoopEnd {
+        start: usize,
+    },
+    /// A `[-]` or `[+]` loop, `increment` tells which
+    SetZero {
+        index: usize,
+        increment: bool,
+    },
+    /// Run the multiply loop `multiply` as arithmetic and jump to its
+    /// `LoopEnd` at `end`. When the body would step off the tape, falls
+    /// through to the loop itself so the pointer mode applies.
+    Multiply {
+        index: usize,
+        multiply: usize,
+        end: usize,
+    },
+}
+
+#[derive(Debug)]
+pub struct Bytecode {
+    pub instructions: Vec<Instruction>,
+    /// Position of every loop and the number of times its body ran, in
+    /// source order
+    pub(crate) loops: Vec<(Position, usize)>,
+    pub multiplies: Vec<Multiply>,
+    max_depth: usize,
+    opt_level: OptLevel,
+}
+
+impl Bytecode {
+    pub fn compile(program: &Program, opt_level: OptLevel) -> Self {
+        let mut bytecode = Self {
+            instructions: Vec::with_capacity(program.total_operators()),
+            loops: Vec::new(),
+            multiplies: Vec::new(),
+            max_depth: program.max_depth(),
+            opt_level,
+        };
+        bytecode.lower(program);
+        bytecode
+    }
+
+    fn lower(&mut self, program: &Program) {
+        for node in program.commands.iter() {
+            match node {
+                Node::Op(operator) => self.instructions.push(Instruction::Operator(*operator)),
+                Node::Loop(iteration) => {
+                    let index = self.loops.len();
+                    if let (
+                        OptLevel::Clear | OptLevel::Arithmetic,
+                        [Node::Op(operator @ (Operator::Decrement(1) | Operator::Increment(1)))],
+                    ) = (self.opt_level, iteration.program.commands.as_slice())
+                    {
+                        self.loops.push((iteration.position, 0));
+                        self.instructions.push(Instruction::SetZero {
+                            index,
+                            increment: matches!(operator, Operator::Increment(_)),
+                        });
+                        continue;
+                    }
+
+                    let multiply = match self.opt_level {
+                        OptLevel::Arithmetic => Multiply::detect(&iteration.program),
+                        _ => None,
+                    };
+                    // Patched along with the `LoopStart`, the loop follows
+                    // as the fallback
+                    let multiply = multiply.map(|multiply| {
+                        self.instructions.push(Instruction::Multiply {
+                            index,
+                            multiply: self.multiplies.len(),
+                            end: 0,
+                        });
+                        self.multiplies.push(multiply);
+                        self.instructions.len() - 1
+                    });
+
+                    let start = self.instructions.len();
+                    self.loops.push((iteration.position, 0));
+                    // Patched once the body is lowered
+                    self.instructions
+                        .push(Instruction::LoopStart { end: 0, index });
+                    self.lower(&iteration.program);
+
+                    let end = self.instructions.len();
+                    self.instructions.push(Instruction::LoopEnd { start });
+                    self.instructions[start] = Instruction::LoopStart { end, index };
+                    if let Some(Instruction::Multiply { end: target, .. }) =
+                        multiply.map(|i| &mut self.instructions[i])
+                    {
+                        *target = end;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+}
+
+impl Interpret for Bytecode {
+    fn interpret(&mut self, state: &mut State) -> Result<(), RuntimeError> {
+        interpreter::execute(self, state)
+    }
+}
+
+impl Profile for Bytecode {
+    fn loop_counts(&self) -> Vec<(Position, usize)> {
+        self.loops.clone()
+    }
+
+    fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+}
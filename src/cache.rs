@@ -0,0 +1,71 @@
+//! # Execution Cache
+//! Remembers the final state of a program run from a given tape, pointer
+//! and input, so that running an identical program again is served from
+//! memory.
+//!
+//! Interpretation is deterministic as long as all input is known upfront,
+//! so programs fed from a live stdin stream must not go through the cache.
+//!
+//! Every entry holds two copies of the tape, so only the most recently used
+//! runs are kept.
+
+use std::collections::VecDeque;
+
+use crate::{
+    interpreter::{Interpreter, RuntimeError, State},
+    parser::Program,
+};
+
+/// Tape, pointer and input a run starts from
+type Start = (Vec<u8>, usize, Vec<u8>);
+
+/// Runs kept before the least recently used one is dropped
+const CACHE_SIZE: usize = 16;
+
+#[derive(Debug, Default)]
+pub struct ExecutionCache {
+    /// Least recently used first
+    entries: VecDeque<(Program, Start, State)>,
+    hits: usize,
+}
+
+impl ExecutionCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Interpret the program unless an identical run is cached.
+    /// Returns whether the result came from the cache, failed runs are
+    /// not cached.
+    pub fn interpret(
+        &mut self,
+        interpreter: &mut Interpreter,
+        mut program: Program,
+    ) -> Result<bool, RuntimeError> {
+        let state = &interpreter.state;
+        let start = (state.memory.clone(), state.pointer(), state.input.clone());
+
+        let found = self
+            .entries
+            .iter()
+            .position(|(cached, from, _)| *cached == program && *from == start);
+        if let Some(entry) = found.and_then(|index| self.entries.remove(index)) {
+            interpreter.state = entry.2.clone();
+            self.entries.push_back(entry);
+            self.hits += 1;
+            return Ok(true);
+        }
+
+        interpreter.interpret(&mut program)?;
+        if self.entries.len() == CACHE_SIZE {
+            self.entries.pop_front();
+        }
+        self.entries
+            .push_back((program, start, interpreter.state.clone()));
+        Ok(false)
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+}
@@ -0,0 +1,102 @@
+//! # Encryption
+//! Programs can be stored encrypted with a passphrase. The key is derived
+//! with PBKDF2-HMAC-SHA256 over a random salt and the program bytes are
+//! XORed with a ChaCha20 keystream under a random nonce. Salt and nonce are
+//! stored in the header, so no two images share a keystream.
+//!
+//! Along with the key, the derivation yields a short check value that is
+//! stored in the header, so a wrong passphrase is caught before decrypting.
+
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+
+const ITERATIONS: u32 = 4096;
+
+/// Salt of images written before salts were random, their nonce is zero
+const LEGACY_SALT: &[u8] = b"brainfreeze";
+
+pub const SALT_LENGTH: usize = 16;
+pub const NONCE_LENGTH: usize = 12;
+pub const CHECK_LENGTH: usize = 4;
+
+/// What the header stores about an encrypted program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Encryption {
+    pub salt: Vec<u8>,
+    pub nonce: [u8; NONCE_LENGTH],
+    pub check: [u8; CHECK_LENGTH],
+}
+
+impl Encryption {
+    /// Check value, salt and nonce, in that order
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.check.to_vec();
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+
+    /// Read the header field, older images only store the check value
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let check = bytes.get(..CHECK_LENGTH)?.try_into().ok()?;
+        match &bytes[CHECK_LENGTH..] {
+            [] => Some(Self {
+                salt: LEGACY_SALT.to_vec(),
+                nonce: [0; NONCE_LENGTH],
+                check,
+            }),
+            rest if rest.len() == SALT_LENGTH + NONCE_LENGTH => Some(Self {
+                salt: rest[..SALT_LENGTH].to_vec(),
+                nonce: rest[SALT_LENGTH..].try_into().ok()?,
+                check,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub struct Key {
+    key: [u8; 32],
+    pub encryption: Encryption,
+}
+
+impl Key {
+    /// Derive a key for a new program with a fresh salt and nonce
+    pub fn generate(password: &str) -> Result<Self, getrandom::Error> {
+        let mut salt = vec![0; SALT_LENGTH];
+        let mut nonce = [0; NONCE_LENGTH];
+        getrandom::getrandom(&mut salt)?;
+        getrandom::getrandom(&mut nonce)?;
+        Ok(Self::derive(password, salt, nonce))
+    }
+
+    /// Derive the key a program was encrypted with, `None` when the
+    /// password doesn't match the check value
+    pub fn recover(password: &str, encryption: &Encryption) -> Option<Self> {
+        let key = Self::derive(password, encryption.salt.clone(), encryption.nonce);
+        (key.encryption.check == encryption.check).then_some(key)
+    }
+
+    fn derive(password: &str, salt: Vec<u8>, nonce: [u8; NONCE_LENGTH]) -> Self {
+        let mut derived = [0; 32 + CHECK_LENGTH];
+        pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password.as_bytes(), &salt, ITERATIONS, &mut derived);
+
+        let mut key = [0; 32];
+        key.copy_from_slice(&derived[..32]);
+        let mut check = [0; CHECK_LENGTH];
+        check.copy_from_slice(&derived[32..]);
+
+        Self {
+            key,
+            encryption: Encryption { salt, nonce, check },
+        }
+    }
+
+    /// Encrypt or decrypt `data` in place, both are the same operation
+    pub fn apply(&self, data: &mut [u8]) {
+        let mut cipher = ChaCha20::new(&self.key.into(), &self.encryption.nonce.into());
+        cipher.apply_keystream(data);
+    }
+}
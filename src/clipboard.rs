@@ -0,0 +1,67 @@
+//! # Clipboard
+//! Reading Brainf*ck source from the system clipboard.
+//!
+//! System access lives behind the `clipboard` feature, everything else
+//! only talks to the [`Clipboard`] trait.
+
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub enum ClipboardError {
+    #[cfg_attr(feature = "clipboard", allow(dead_code))]
+    Unsupported,
+    #[cfg_attr(not(feature = "clipboard"), allow(dead_code))]
+    Unavailable,
+    Empty,
+}
+
+impl Display for ClipboardError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClipboardError::Unsupported => formatter
+                .write_str("built without clipboard support, enable the `clipboard` feature"),
+            ClipboardError::Unavailable => formatter.write_str("failed to access the clipboard"),
+            ClipboardError::Empty => formatter.write_str("clipboard has no text"),
+        }
+    }
+}
+
+pub trait Clipboard {
+    fn text(&mut self) -> Result<String, ClipboardError>;
+}
+
+#[cfg(feature = "clipboard")]
+pub struct SystemClipboard(arboard::Clipboard);
+
+#[cfg(feature = "clipboard")]
+impl Clipboard for SystemClipboard {
+    fn text(&mut self) -> Result<String, ClipboardError> {
+        self.0.get_text().map_err(|e| match e {
+            arboard::Error::ContentNotAvailable => ClipboardError::Empty,
+            _ => ClipboardError::Unavailable,
+        })
+    }
+}
+
+/// Open the system clipboard
+pub fn system() -> Result<Box<dyn Clipboard>, ClipboardError> {
+    #[cfg(feature = "clipboard")]
+    {
+        let clipboard = arboard::Clipboard::new().map_err(|_| ClipboardError::Unavailable)?;
+        Ok(Box::new(SystemClipboard(clipboard)))
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    {
+        Err(ClipboardError::Unsupported)
+    }
+}
+
+/// Read a program from the clipboard, rejecting blank contents
+pub fn read_program(clipboard: &mut dyn Clipboard) -> Result<String, ClipboardError> {
+    let text = clipboard.text()?;
+    if text.trim().is_empty() {
+        return Err(ClipboardError::Empty);
+    }
+    Ok(text)
+}
@@ -0,0 +1,103 @@
+//! # Compression
+//! A small LZ77 style compressor for program source.
+//!
+//! The output is a sequence of chunks, each starting with a control byte:
+//!
+//! ```plaintext
+//! 0b0nnnnnnn                 : n + 1 literal bytes follow
+//! 0b1nnnnnnn distance: u16   : copy n + 3 bytes from `distance` bytes back
+//! ```
+
+use std::collections::HashMap;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 0x7f;
+const MAX_LITERALS: usize = 0x80;
+const WINDOW: usize = u16::MAX as usize;
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut literals: Vec<u8> = Vec::new();
+    // Last position each 3 byte prefix was seen at
+    let mut seen: HashMap<&[u8], usize> = HashMap::new();
+    let mut i = 0;
+
+    while i < input.len() {
+        let key = input.get(i..i + MIN_MATCH);
+        let candidate = key
+            .and_then(|key| seen.get(key))
+            .copied()
+            .filter(|start| i - start <= WINDOW);
+        if let Some(key) = key {
+            seen.insert(key, i);
+        }
+
+        let length = candidate.map_or(0, |start| {
+            input[i..]
+                .iter()
+                .zip(&input[start..])
+                .take(MAX_MATCH)
+                .take_while(|(a, b)| a == b)
+                .count()
+        });
+
+        match candidate {
+            Some(start) if length >= MIN_MATCH => {
+                flush_literals(&mut output, &mut literals);
+                output.push(0x80 | (length - MIN_MATCH) as u8);
+                output.extend_from_slice(&((i - start) as u16).to_le_bytes());
+                for j in i + 1..i + length {
+                    if let Some(key) = input.get(j..j + MIN_MATCH) {
+                        seen.insert(key, j);
+                    }
+                }
+                i += length;
+            }
+            _ => {
+                literals.push(input[i]);
+                if literals.len() == MAX_LITERALS {
+                    flush_literals(&mut output, &mut literals);
+                }
+                i += 1;
+            }
+        }
+    }
+
+    flush_literals(&mut output, &mut literals);
+    output
+}
+
+fn flush_literals(output: &mut Vec<u8>, literals: &mut Vec<u8>) {
+    if literals.is_empty() {
+        return;
+    }
+    output.push((literals.len() - 1) as u8);
+    output.append(literals);
+}
+
+/// Decompress the output of [`compress`], `None` if it is corrupt
+pub fn decompress(mut input: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+
+    while let Some((&control, rest)) = input.split_first() {
+        if control & 0x80 == 0 {
+            let count = control as usize + 1;
+            output.extend_from_slice(rest.get(..count)?);
+            input = &rest[count..];
+        } else {
+            let length = (control & 0x7f) as usize + MIN_MATCH;
+            let distance = u16::from_le_bytes([*rest.first()?, *rest.get(1)?]) as usize;
+            let start = output.len().checked_sub(distance)?;
+            if distance == 0 {
+                return None;
+            }
+            // Copy byte by byte, matches can overlap what they produce
+            for j in start..start + length {
+                output.push(output[j]);
+            }
+            input = &rest[2..];
+        }
+    }
+
+    Some(output)
+}
@@ -0,0 +1,154 @@
+//! # Direct Interpretation
+//! Runs the token stream as is, without building the AST. Bracket jumps
+//! are resolved once upfront so that loops are a single index change.
+
+use crate::{
+    interpreter::{Interpret, RuntimeError, State},
+    lexer::{Position, Token, TokenKind},
+    parser::{Operator, ParseError},
+    stats::Profile,
+};
+
+#[derive(Debug)]
+enum Instruction {
+    Operator(Operator),
+    /// Jump past the matching `]` when the cell is zero
+    LoopStart {
+        end: usize,
+        position: Position,
+        iterations: usize,
+    },
+    /// Jump back to the matching `[` when the cell is not zero
+    LoopEnd(usize),
+}
+
+#[derive(Debug)]
+pub struct DirectProgram {
+    instructions: Vec<Instruction>,
+    max_depth: usize,
+}
+
+impl DirectProgram {
+    pub fn new(tokens: &[Token]) -> Result<Self, Vec<ParseError>> {
+        let mut instructions = Vec::with_capacity(tokens.len());
+        let mut errors = Vec::new();
+        let mut open: Vec<(usize, &Token)> = Vec::new();
+        let mut max_depth = 0;
+
+        for token in tokens {
+            let index = instructions.len();
+            match token.kind() {
+                TokenKind::LoopStart => {
+                    open.push((index, token));
+                    max_depth = max_depth.max(open.len());
+                    // Patched once the matching `]` is found
+                    instructions.push(Instruction::LoopStart {
+                        end: 0,
+                        position: *token.position(),
+                        iterations: 0,
+                    });
+                }
+                TokenKind::LoopEnd => match open.pop() {
+                    Some((start, _)) => {
+                        if let Instruction::LoopStart { end, .. } = &mut instructions[start] {
+                            *end = index;
+                        }
+                        instructions.push(Instruction::LoopEnd(start));
+                    }
+                    None => errors.push((
+                        *token.position(),
+                        "Unmatched ']', there is no '[' to close".to_string(),
+                    )),
+                },
+                TokenKind::EOF => break,
+                kind => instructions.extend(Operator::from_kind(kind).map(Instruction::Operator)),
+            }
+        }
+
+        // Reported where the `]` was expected, innermost first like the parser
+        let end = tokens
+            .last()
+            .map_or(Position::new(0, 0), |token| *token.position());
+        for (_, token) in open.into_iter().rev() {
+            errors.push((end, format!("Unclosed '[' opened at {}", token.position())));
+        }
+
+        if errors.is_empty() {
+            Ok(Self {
+                instructions,
+                max_depth,
+            })
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl Interpret for DirectProgram {
+    fn interpret(&mut self, state: &mut State) -> Result<(), RuntimeError> {
+        let mut current = 0;
+        // Loop guard snapshots and iteration counts of the loops being run,
+        // innermost last
+        let mut snapshots = Vec::new();
+
+        while let Some(instruction) = self.instructions.get_mut(current) {
+            match instruction {
+                Instruction::Operator(operator) => operator.interpret(state)?,
+                Instruction::LoopStart {
+                    end, iterations, ..
+                } => {
+                    if state.read() == 0 {
+                        current = *end;
+                    } else {
+                        state.step()?;
+                        *iterations += 1;
+                        state.check_loop_iterations(1)?;
+                        snapshots.push((state.loop_snapshot(), 1));
+                    }
+                }
+                Instruction::LoopEnd(start) => {
+                    let start = *start;
+                    if state.read() != 0 {
+                        if let Some((before, cycles)) = snapshots.last_mut() {
+                            state.check_loop_progress(*before)?;
+                            *before = state.loop_snapshot();
+                            *cycles += 1;
+                            state.check_loop_iterations(*cycles)?;
+                        }
+                        current = start;
+                        state.step()?;
+                        if let Instruction::LoopStart { iterations, .. } =
+                            &mut self.instructions[start]
+                        {
+                            *iterations += 1;
+                        }
+                    } else {
+                        snapshots.pop();
+                    }
+                }
+            }
+            current += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Profile for DirectProgram {
+    fn loop_counts(&self) -> Vec<(Position, usize)> {
+        self.instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::LoopStart {
+                    position,
+                    iterations,
+                    ..
+                } => Some((*position, *iterations)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+}
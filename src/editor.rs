@@ -0,0 +1,74 @@
+//! # Line Editor
+//! Line editing for the REPL through rustyline: cursor movement, history
+//! recall with the arrow keys and reverse search with Ctrl-R. History is
+//! kept in `~/.brainfreeze_history` between sessions when stdin is a
+//! terminal, trimmed to the latest lines.
+
+use std::{io::IsTerminal, path::PathBuf};
+
+use rustyline::{error::ReadlineError, history::FileHistory, Config};
+
+const HISTORY_FILE: &str = ".brainfreeze_history";
+
+/// Lines kept in the history file, older lines are dropped
+const HISTORY_SIZE: usize = 1000;
+
+/// What reading a line ended with
+pub enum Input {
+    Line(String),
+    /// Ctrl-C, the line being edited is thrown away
+    Interrupted,
+    /// Ctrl-D on an empty line or a closed stdin
+    Eof,
+}
+
+pub struct Editor {
+    inner: rustyline::Editor<(), FileHistory>,
+    history_path: Option<PathBuf>,
+}
+
+impl Editor {
+    /// Create an editor, loading the history when stdin is a terminal
+    pub fn new() -> Result<Self, ReadlineError> {
+        let config = Config::builder()
+            .max_history_size(HISTORY_SIZE)?
+            .auto_add_history(false)
+            .build();
+        let mut inner = rustyline::Editor::with_config(config)?;
+
+        let history_path = std::env::var_os("HOME")
+            .filter(|_| std::io::stdin().is_terminal())
+            .map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        if let Some(path) = &history_path {
+            // There is no history file before the first session
+            let _ = inner.load_history(path);
+        }
+
+        Ok(Self {
+            inner,
+            history_path,
+        })
+    }
+
+    /// Print `prompt` and read a line without its line ending
+    pub fn read_line(&mut self, prompt: &str) -> Result<Input, ReadlineError> {
+        match self.inner.readline(prompt) {
+            Ok(line) => Ok(Input::Line(line)),
+            Err(ReadlineError::Interrupted) => Ok(Input::Interrupted),
+            Err(ReadlineError::Eof) => Ok(Input::Eof),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remember a line so it can be recalled, saving it to the history file
+    pub fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+
+        // History is a convenience, failing to save it shouldn't stop the REPL
+        if let (Ok(true), Some(path)) = (self.inner.add_history_entry(line), &self.history_path) {
+            let _ = self.inner.append_history(path);
+        }
+    }
+}
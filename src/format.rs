@@ -0,0 +1,124 @@
+//! # Formatting
+//! Rewrites Brainf*ck source in a canonical layout: runs of operators share
+//! a line, loops without nested loops stay on one line and every other loop
+//! has its body indented between a `[` and `]` line of their own.
+
+use crate::lexer::{Comment, Token, TokenKind};
+
+const INDENT: &str = "  ";
+
+/// Format tokens with balanced brackets, comments are kept on their own line
+/// after `comment_char`
+pub fn format(tokens: &[Token], comments: &[Comment], comment_char: char) -> String {
+    let closing = matching_brackets(tokens);
+    let mut formatter = Formatter {
+        comment_char,
+        ..Formatter::default()
+    };
+    let mut comments = comments.iter().peekable();
+    let mut index = 0;
+
+    while let Some(token) = tokens.get(index) {
+        while let Some(comment) = comments.next_if(|c| c.token_index <= index) {
+            formatter.comment(&comment.text);
+        }
+
+        match token.kind() {
+            TokenKind::LoopStart => {
+                let end = closing[index].expect("balanced brackets");
+                let inline = tokens[index + 1..end]
+                    .iter()
+                    .all(|token| !matches!(token.kind(), TokenKind::LoopStart))
+                    && !comments
+                        .clone()
+                        .any(|c| (index + 1..=end).contains(&c.token_index));
+
+                formatter.flush();
+                if inline {
+                    let loop_tokens = &tokens[index..=end];
+                    formatter.current = loop_tokens.iter().map(symbols).collect();
+                    formatter.flush();
+                    index = end;
+                } else {
+                    formatter.line("[");
+                    formatter.depth += 1;
+                }
+            }
+            TokenKind::LoopEnd => {
+                formatter.flush();
+                formatter.depth = formatter.depth.saturating_sub(1);
+                formatter.line("]");
+            }
+            TokenKind::EOF => break,
+            _ => formatter.current.push_str(&symbols(token)),
+        }
+        index += 1;
+    }
+
+    for comment in comments {
+        formatter.comment(&comment.text);
+    }
+    formatter.flush();
+
+    formatter.output
+}
+
+/// Source characters of the token, repeated for stacked tokens
+fn symbols(token: &Token) -> String {
+    token
+        .kind()
+        .as_char()
+        .map(|symbol| symbol.to_string().repeat(token.kind().count()))
+        .unwrap_or_default()
+}
+
+/// Index of the matching `]` for every `[`
+fn matching_brackets(tokens: &[Token]) -> Vec<Option<usize>> {
+    let mut closing = vec![None; tokens.len()];
+    let mut open = Vec::new();
+
+    for (index, token) in tokens.iter().enumerate() {
+        match token.kind() {
+            TokenKind::LoopStart => open.push(index),
+            TokenKind::LoopEnd => {
+                if let Some(start) = open.pop() {
+                    closing[start] = Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    closing
+}
+
+#[derive(Default)]
+struct Formatter {
+    output: String,
+    /// Operators waiting to be written on the current line
+    current: String,
+    depth: usize,
+    comment_char: char,
+}
+
+impl Formatter {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.depth {
+            self.output.push_str(INDENT);
+        }
+        self.output.push_str(text);
+        self.output.push('\n');
+    }
+
+    fn flush(&mut self) {
+        if !self.current.is_empty() {
+            let current = std::mem::take(&mut self.current);
+            self.line(&current);
+        }
+    }
+
+    fn comment(&mut self, text: &str) {
+        self.flush();
+        self.line(&format!("{}{}", self.comment_char, text));
+    }
+}
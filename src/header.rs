@@ -0,0 +1,200 @@
+//! # Format Header
+//!
+//! Images written by `brainfreeze` start with a small header describing the
+//! embedded program. The header is a byte string laid out as:
+//!
+//! ```plaintext
+//! magic    : "BFZ"
+//! version  : u8
+//! length   : u16 (little endian), size of the fields below
+//! fields   : (tag: u8, length: u16, data: [u8; length])*
+//! ```
+//!
+//! Fields are tagged so that readers can skip the ones they don't know.
+
+use std::fmt::Display;
+
+use crate::{cipher::Encryption, lexer::Comment};
+
+pub const MAGIC: &[u8; 3] = b"BFZ";
+pub const VERSION: u8 = 1;
+
+/// Size of magic, version and length
+pub const PREAMBLE_LENGTH: usize = 6;
+
+const TAG_META: u8 = 0x01;
+const TAG_COMMENT: u8 = 0x02;
+const TAG_ENCODING: u8 = 0x03;
+const TAG_COMPRESSION: u8 = 0x04;
+const TAG_ENCRYPTION: u8 = 0x05;
+const TAG_TOKEN_COUNT: u8 = 0x06;
+
+#[derive(Debug)]
+pub enum HeaderError {
+    Truncated,
+    UnsupportedVersion(u8),
+    InvalidUtf8,
+    TooLarge,
+    UnknownEncoding,
+}
+
+impl Display for HeaderError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderError::Truncated => formatter.write_str("header is truncated"),
+            HeaderError::UnsupportedVersion(version) => {
+                formatter.write_fmt(format_args!("unsupported header version {}", version))
+            }
+            HeaderError::InvalidUtf8 => formatter.write_str("header text is not valid UTF-8"),
+            HeaderError::TooLarge => formatter.write_str("header is too large"),
+            HeaderError::UnknownEncoding => formatter.write_str("unknown token encoding"),
+        }
+    }
+}
+
+/// How tokens are laid out in the pixels following the header
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    /// A token is a constant color change between two pixels
+    #[default]
+    Delta,
+    /// A token is the position of the odd parity pixel inside a block
+    Position,
+    /// A token is stored in the lowest bits of the channels, leaving the
+    /// image visually unchanged
+    Lsb,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Key/value pairs such as author or description
+    pub metadata: Vec<(String, String)>,
+    /// Source comments, kept so they can be reattached on extraction
+    pub comments: Vec<Comment>,
+    /// Token encoding, older images without the field use deltas
+    pub encoding: Encoding,
+    /// Program is stored as compressed source bytes instead of tokens
+    pub compressed: bool,
+    /// Salt, nonce and key check the program bytes are encrypted with, the
+    /// program is stored as bytes like compressed ones
+    pub encryption: Option<Encryption>,
+    /// Number of tokens in the program, `EOF` included, so reading stops
+    /// there even if the `EOF` pixel is damaged
+    pub token_count: Option<u32>,
+}
+
+impl Header {
+    pub fn encode(&self) -> Result<Vec<u8>, HeaderError> {
+        let mut fields = Vec::new();
+
+        for (key, value) in &self.metadata {
+            let key_length = u8::try_from(key.len()).map_err(|_| HeaderError::TooLarge)?;
+            let mut data = vec![key_length];
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(value.as_bytes());
+            push_field(&mut fields, TAG_META, &data)?;
+        }
+
+        for comment in &self.comments {
+            let index = u32::try_from(comment.token_index).map_err(|_| HeaderError::TooLarge)?;
+            let mut data = index.to_le_bytes().to_vec();
+            data.extend_from_slice(comment.text.as_bytes());
+            push_field(&mut fields, TAG_COMMENT, &data)?;
+        }
+
+        if self.encoding != Encoding::Delta {
+            push_field(&mut fields, TAG_ENCODING, &[self.encoding as u8])?;
+        }
+
+        if self.compressed {
+            push_field(&mut fields, TAG_COMPRESSION, &[1])?;
+        }
+
+        if let Some(encryption) = &self.encryption {
+            push_field(&mut fields, TAG_ENCRYPTION, &encryption.to_bytes())?;
+        }
+
+        if let Some(count) = self.token_count {
+            push_field(&mut fields, TAG_TOKEN_COUNT, &count.to_le_bytes())?;
+        }
+
+        let length = u16::try_from(fields.len()).map_err(|_| HeaderError::TooLarge)?;
+        let mut bytes = Vec::with_capacity(PREAMBLE_LENGTH + fields.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&length.to_le_bytes());
+        bytes.extend_from_slice(&fields);
+
+        Ok(bytes)
+    }
+
+    /// Decode the fields following the preamble
+    pub fn decode_fields(version: u8, mut fields: &[u8]) -> Result<Self, HeaderError> {
+        if version != VERSION {
+            return Err(HeaderError::UnsupportedVersion(version));
+        }
+
+        let mut header = Header::default();
+
+        while !fields.is_empty() {
+            if fields.len() < 3 {
+                return Err(HeaderError::Truncated);
+            }
+            let tag = fields[0];
+            let length = u16::from_le_bytes([fields[1], fields[2]]) as usize;
+            let data = fields.get(3..3 + length).ok_or(HeaderError::Truncated)?;
+            fields = &fields[3 + length..];
+
+            match tag {
+                TAG_META => {
+                    let key_length = *data.first().ok_or(HeaderError::Truncated)? as usize;
+                    let key = data.get(1..1 + key_length).ok_or(HeaderError::Truncated)?;
+                    let value = &data[1 + key_length..];
+                    header.metadata.push((to_string(key)?, to_string(value)?));
+                }
+                TAG_COMMENT => {
+                    let index = data.get(..4).ok_or(HeaderError::Truncated)?;
+                    let index = u32::from_le_bytes([index[0], index[1], index[2], index[3]]);
+                    let text = to_string(&data[4..])?;
+                    header.comments.push(Comment::new(index as usize, text));
+                }
+                TAG_ENCODING => {
+                    header.encoding = match data.first() {
+                        Some(0) => Encoding::Delta,
+                        Some(1) => Encoding::Position,
+                        Some(2) => Encoding::Lsb,
+                        Some(_) => return Err(HeaderError::UnknownEncoding),
+                        None => return Err(HeaderError::Truncated),
+                    };
+                }
+                TAG_COMPRESSION => {
+                    header.compressed = *data.first().ok_or(HeaderError::Truncated)? != 0;
+                }
+                TAG_ENCRYPTION => {
+                    header.encryption =
+                        Some(Encryption::from_bytes(data).ok_or(HeaderError::Truncated)?);
+                }
+                TAG_TOKEN_COUNT => {
+                    let count = data.get(..4).ok_or(HeaderError::Truncated)?;
+                    header.token_count =
+                        Some(u32::from_le_bytes([count[0], count[1], count[2], count[3]]));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(header)
+    }
+}
+
+fn push_field(fields: &mut Vec<u8>, tag: u8, data: &[u8]) -> Result<(), HeaderError> {
+    let length = u16::try_from(data.len()).map_err(|_| HeaderError::TooLarge)?;
+    fields.push(tag);
+    fields.extend_from_slice(&length.to_le_bytes());
+    fields.extend_from_slice(data);
+    Ok(())
+}
+
+fn to_string(bytes: &[u8]) -> Result<String, HeaderError> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| HeaderError::InvalidUtf8)
+}
@@ -1,26 +1,64 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter, Read},
+    iter::Peekable,
+    ops::Range,
+    path::Path,
+};
 
-use image::{io::Reader as ImageReader, ImageBuffer, Rgba, RgbaImage};
+use image::{
+    codecs::png::PngDecoder, io::Reader as ImageReader, AnimationDecoder, ColorType, DynamicImage,
+    ImageBuffer, ImageDecoder, Luma, Rgba, RgbaImage,
+};
 
-use crate::lexer::{Position, Token, TokenKind};
+use crate::{
+    binary,
+    cipher::Key,
+    compress,
+    header::{Encoding, Header, HeaderError, MAGIC, PREAMBLE_LENGTH},
+    lexer::{self, Lexer, Position, Token, TokenKind},
+};
 
 #[derive(Debug)]
 pub enum ReadError {
     InvalidFileExtension,
+    LossyFormat,
     FileNotFound,
     DecodeFailed,
+    NoProgramFound,
+    InvalidHeader(HeaderError),
+    CorruptProgram,
+    PasswordRequired,
+    WrongPassword,
+    NoHeader,
 }
 
 impl Display for ReadError {
     fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ReadError::InvalidFileExtension => {
-                formatter.write_str("invalid file extension, must be .png")
+                formatter.write_str("invalid file extension, must be .png, .bmp or .tiff")
             }
+            ReadError::LossyFormat => formatter.write_str(LOSSY_FORMAT),
             ReadError::FileNotFound => formatter.write_str("file not found"),
             ReadError::DecodeFailed => {
-                formatter.write_str("failed to decode image, are you sure it's a png?")
+                formatter.write_str("failed to decode image, is it in a supported format?")
             }
+            ReadError::NoProgramFound => formatter.write_str(
+                "no program found, was the image injected with different encoding parameters?",
+            ),
+            ReadError::InvalidHeader(e) => {
+                formatter.write_fmt(format_args!("invalid header, {}", e))
+            }
+            ReadError::CorruptProgram => formatter.write_str("stored program is corrupt"),
+            ReadError::PasswordRequired => {
+                formatter.write_str("program is encrypted, a password is needed")
+            }
+            ReadError::WrongPassword => formatter.write_str("wrong password"),
+            ReadError::NoHeader => formatter.write_str(
+                "no brainfreeze header found, the image has no program or predates the header",
+            ),
         }
     }
 }
@@ -28,22 +66,129 @@ impl Display for ReadError {
 #[derive(Debug)]
 pub enum WriteError {
     InvalidFileExtension,
+    LossyFormat,
+    AnimatedFormat,
     FileNotFound,
     DecodeFailed,
     SaveFailed,
+    InvalidHeader(HeaderError),
+    ImageTooSmall,
+    AnimatedCompression,
+    RandomUnavailable,
+    /// Pixels after the header the program needs and the cover has
+    ProgramTooLarge {
+        needed: usize,
+        available: usize,
+    },
 }
 
 impl Display for WriteError {
     fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             WriteError::InvalidFileExtension => {
-                formatter.write_str("invalid file extension, must be .png")
+                formatter.write_str("invalid file extension, must be .png, .bmp or .tiff")
             }
+            WriteError::LossyFormat => formatter.write_str(LOSSY_FORMAT),
+            WriteError::AnimatedFormat => formatter.write_str("animated images must be .png"),
             WriteError::FileNotFound => formatter.write_str("file not found"),
             WriteError::DecodeFailed => {
-                formatter.write_str("failed to decode image, are you sure it's a png?")
+                formatter.write_str("failed to decode image, is it in a supported format?")
             }
             WriteError::SaveFailed => formatter.write_str("failed to save image"),
+            WriteError::InvalidHeader(e) => {
+                formatter.write_fmt(format_args!("invalid header, {}", e))
+            }
+            WriteError::ImageTooSmall => {
+                formatter.write_str("image is too small to hold the header")
+            }
+            WriteError::AnimatedCompression => {
+                formatter.write_str("compression is not supported for animated images")
+            }
+            WriteError::RandomUnavailable => {
+                formatter.write_str("failed to generate a salt for the password")
+            }
+            WriteError::ProgramTooLarge { needed, available } => formatter.write_fmt(format_args!(
+                "program needs {} pixels but the image only has {} after the header",
+                needed, available
+            )),
+        }
+    }
+}
+
+const LOSSY_FORMAT: &str =
+    "lossy formats like JPEG can't be used, their compression destroys the encoding";
+
+/// Formats that store pixels exactly, so the encoding survives saving
+const LOSSLESS_EXTENSIONS: [&str; 4] = ["png", "bmp", "tif", "tiff"];
+
+/// Formats that are supported by the decoder but lose the encoding
+const LOSSY_EXTENSIONS: [&str; 2] = ["jpg", "jpeg"];
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+#[inline]
+fn is_png(path: &str) -> bool {
+    extension(path) == "png"
+}
+
+#[inline]
+fn is_lossless(path: &str) -> bool {
+    LOSSLESS_EXTENSIONS.contains(&extension(path).as_str())
+}
+
+#[inline]
+fn is_lossy(path: &str) -> bool {
+    LOSSY_EXTENSIONS.contains(&extension(path).as_str())
+}
+
+/// Reject images the program can't be written to or read from
+fn check_write_format(path: &str) -> Result<(), WriteError> {
+    if is_lossy(path) {
+        return Err(WriteError::LossyFormat);
+    }
+    if !is_lossless(path) {
+        return Err(WriteError::InvalidFileExtension);
+    }
+    Ok(())
+}
+
+/// Channel layout of the cover. Grayscale covers are handled as RGBA with
+/// every channel holding the luma, and every change keeps the channels
+/// equal so that the image can be saved back as grayscale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorMode {
+    Rgba,
+    Luma,
+}
+
+impl ColorMode {
+    fn of(color: ColorType) -> Self {
+        match color {
+            ColorType::L8 | ColorType::L16 => ColorMode::Luma,
+            _ => ColorMode::Rgba,
+        }
+    }
+
+    /// Spread the luma into every channel of the RGBA pixels
+    fn expand(&self, pixels: &mut [Rgba<u8>]) {
+        if *self == ColorMode::Luma {
+            for pixel in pixels {
+                pixel.0 = [pixel.0[0]; 4];
+            }
+        }
+    }
+
+    /// Pixels it takes to store a byte
+    fn pixels_per_byte(&self) -> usize {
+        match self {
+            ColorMode::Rgba => 1,
+            ColorMode::Luma => 4,
         }
     }
 }
@@ -57,7 +202,7 @@ fn pixel_distance(a: &image::Rgba<u8>, b: &image::Rgba<u8>) -> Option<u8> {
     let [r2, g2, b2, a2] = b.0;
 
     let diff = r1.abs_diff(r2);
-    if diff < MINIMUM_PIXEL_DISTANCE || diff > MAXIMUM_PIXEL_DISTANCE {
+    if !(MINIMUM_PIXEL_DISTANCE..=MAXIMUM_PIXEL_DISTANCE).contains(&diff) {
         return None;
     }
 
@@ -99,48 +244,179 @@ fn kind_to_distance(kind: &TokenKind) -> u8 {
 }
 
 #[inline]
-fn kind_count(kind: &TokenKind) -> u32 {
+fn increase_kind(kind: &mut TokenKind, count: usize) {
     match kind {
-        TokenKind::Increment(count)
-        | TokenKind::Decrement(count)
-        | TokenKind::Right(count)
-        | TokenKind::Left(count) => *count as u32,
-        _ => 1,
+        TokenKind::Increment(c) => *c += count,
+        TokenKind::Decrement(c) => *c += count,
+        TokenKind::Right(c) => *c += count,
+        TokenKind::Left(c) => *c += count,
+        _ => {}
+    }
+}
+
+/// Pixels in a block of the position encoding, one for every token kind
+const POSITION_BLOCK: usize = 9;
+
+#[inline]
+fn parity(pixel: &Rgba<u8>) -> bool {
+    let [r, g, b, _] = pixel.0;
+    (r ^ g ^ b) & 1 == 1
+}
+
+#[inline]
+fn set_parity(pixel: &mut Rgba<u8>, odd: bool, mode: ColorMode) {
+    if parity(pixel) != odd {
+        match mode {
+            ColorMode::Rgba => pixel.0[2] ^= 1,
+            ColorMode::Luma => pixel.0.iter_mut().for_each(|channel| *channel ^= 1),
+        }
+    }
+}
+
+/// Make only the pixel at `index` odd, `None` leaves the whole block even
+/// which continues a stacking token
+fn mark_block(block: &mut [Rgba<u8>], index: Option<usize>, mode: ColorMode) {
+    for (i, pixel) in block.iter_mut().enumerate() {
+        set_parity(pixel, Some(i) == index, mode);
+    }
+}
+
+/// Index of the odd pixel in a block, `None` if it has more than one
+fn read_block(block: &[Rgba<u8>]) -> Option<Option<usize>> {
+    let mut odd = block.iter().enumerate().filter(|(_, pixel)| parity(pixel));
+    match (odd.next(), odd.next()) {
+        (None, _) => Some(None),
+        (Some((index, _)), None) => Some(Some(index)),
+        _ => None,
     }
 }
 
+/// Token kinds are ordered inside a block the same way as their distances
 #[inline]
-fn increase_kind(kind: &mut TokenKind, count: u32) {
+fn kind_to_index(kind: &TokenKind) -> usize {
+    (kind_to_distance(kind) - MINIMUM_PIXEL_DISTANCE) as usize
+}
+
+/// Bytes of a token in the LSB encoding, its kind index followed by the
+/// amount as a varint for stacking tokens
+fn lsb_bytes(kind: &TokenKind) -> Vec<u8> {
+    let mut bytes = vec![kind_to_index(kind) as u8];
     match kind {
-        TokenKind::Increment(c) => *c += count as usize,
-        TokenKind::Decrement(c) => *c += count as usize,
-        TokenKind::Right(c) => *c += count as usize,
-        TokenKind::Left(c) => *c += count as usize,
+        TokenKind::Increment(amount)
+        | TokenKind::Decrement(amount)
+        | TokenKind::Right(amount)
+        | TokenKind::Left(amount) => binary::push_varint(&mut bytes, *amount),
         _ => {}
     }
+    bytes
 }
 
-pub fn read(path: &str) -> Result<Vec<Token>, ReadError> {
-    if !path.ends_with(".png") {
-        return Err(ReadError::InvalidFileExtension);
+/// Store a byte in the two least significant bits of each channel
+#[inline]
+fn embed_byte(pixel: &mut Rgba<u8>, byte: u8) {
+    for (index, channel) in pixel.0.iter_mut().enumerate() {
+        let bits = (byte >> (6 - index * 2)) & 0b11;
+        *channel = (*channel & !0b11) | bits;
     }
-    let mut tokens = Vec::new();
+}
 
-    let img = ImageReader::open(path)
-        .map_err(|_| ReadError::FileNotFound)?
-        .decode()
-        .map_err(|_| ReadError::DecodeFailed)?
-        .to_rgba8();
-    let (width, height) = img.dimensions();
-    let img: RgbaImage = ImageBuffer::from_vec(width, height, img.into_raw()).unwrap();
+#[inline]
+fn extract_byte(pixel: &Rgba<u8>) -> u8 {
+    pixel
+        .0
+        .iter()
+        .fold(0, |byte, channel| (byte << 2) | (channel & 0b11))
+}
 
-    let mut pixels = img.pixels().peekable();
-    let mut line = 0;
-    let mut column = 0;
+/// Store bytes from the start of `pixels`, returning the number of pixels
+/// used or `None` if they don't fit. Grayscale takes two bits per pixel.
+fn embed_bytes(pixels: &mut [Rgba<u8>], bytes: &[u8], mode: ColorMode) -> Option<usize> {
+    let length = bytes.len() * mode.pixels_per_byte();
+    let pixels = pixels.get_mut(..length)?;
 
-    let mut stacking_token: Option<TokenKind> = None;
+    match mode {
+        ColorMode::Rgba => {
+            for (pixel, byte) in pixels.iter_mut().zip(bytes) {
+                embed_byte(pixel, *byte);
+            }
+        }
+        ColorMode::Luma => {
+            for (pixels, byte) in pixels.chunks_exact_mut(4).zip(bytes) {
+                // Every channel of a pixel carries the same two bits
+                for (index, pixel) in pixels.iter_mut().enumerate() {
+                    let bits = (byte >> (6 - index * 2)) & 0b11;
+                    pixel.0 = [(pixel.0[0] & !0b11) | bits; 4];
+                }
+            }
+        }
+    }
+
+    Some(length)
+}
+
+/// Read `count` bytes stored by [`embed_bytes`]
+fn extract_bytes(pixels: &[Rgba<u8>], count: usize, mode: ColorMode) -> Option<Vec<u8>> {
+    let pixels = pixels.get(..count * mode.pixels_per_byte())?;
+
+    let bytes = match mode {
+        ColorMode::Rgba => pixels.iter().map(extract_byte).collect(),
+        ColorMode::Luma => pixels
+            .chunks_exact(4)
+            .map(|pixels| {
+                pixels
+                    .iter()
+                    .fold(0, |byte, pixel| (byte << 2) | (pixel.0[0] & 0b11))
+            })
+            .collect(),
+    };
+
+    Some(bytes)
+}
+
+/// Read the format header, returning it with the number of pixels it spans.
+/// Images without a header (written by older versions) yield `None`.
+fn read_header(pixels: &[Rgba<u8>], mode: ColorMode) -> Result<Option<(Header, usize)>, ReadError> {
+    let preamble = match extract_bytes(pixels, PREAMBLE_LENGTH, mode) {
+        Some(preamble) => preamble,
+        None => return Ok(None),
+    };
+    if &preamble[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+
+    let version = preamble[3];
+    let length = u16::from_le_bytes([preamble[4], preamble[5]]) as usize;
+    let end = PREAMBLE_LENGTH + length;
+
+    let bytes =
+        extract_bytes(pixels, end, mode).ok_or(ReadError::InvalidHeader(HeaderError::Truncated))?;
+    let header = Header::decode_fields(version, &bytes[PREAMBLE_LENGTH..])
+        .map_err(ReadError::InvalidHeader)?;
+
+    Ok(Some((header, end * mode.pixels_per_byte())))
+}
+
+/// Decode tokens from `pixels` beginning at `start`, stopping after `EOF`
+/// or `limit` tokens
+fn decode_tokens(
+    pixels: &[Rgba<u8>],
+    start: usize,
+    width: u32,
+    first_line: u32,
+    limit: usize,
+) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    let mut pixels = pixels[start..].iter().peekable();
+    let mut line = first_line + start as u32 / width;
+    let mut column = start as u32 % width;
+
+    let mut stacking_token: Option<(TokenKind, Position)> = None;
 
     while let Some(pixel) = pixels.next() {
+        if tokens.len() >= limit {
+            break;
+        }
         column += 1;
 
         let next_pixel = match pixels.peek() {
@@ -149,16 +425,12 @@ pub fn read(path: &str) -> Result<Vec<Token>, ReadError> {
         };
 
         // If we have a distance, we have a token
-        if let Some(distance) = pixel_distance(pixel, &next_pixel) {
+        if let Some(distance) = pixel_distance(pixel, next_pixel) {
             let kind = distance_to_kind(distance).expect("corresponding kind");
 
             // If we have a stacking token, push it
-            if let Some(last_kind) = stacking_token {
-                let count = kind_count(&last_kind);
-                tokens.push(Token::new(
-                    last_kind,
-                    Position::new(line, column - count - 1),
-                ));
+            if let Some((last_kind, position)) = stacking_token.take() {
+                tokens.push(Token::new(last_kind, position));
             }
 
             match kind {
@@ -167,26 +439,23 @@ pub fn read(path: &str) -> Result<Vec<Token>, ReadError> {
                 | TokenKind::Right(_)
                 | TokenKind::Left(_) => {
                     // Set the stacking token
-                    stacking_token = Some(kind);
+                    stacking_token = Some((kind, Position::new(line, column - 1)));
                 }
                 _ => {
                     // Push the token
                     if matches!(kind, TokenKind::EOF) {
-                        tokens.push(Token::new(kind, Position::new(line, column)));
-                        stacking_token = None;
+                        tokens.push(Token::new(kind, Position::new(line, column - 1)));
                         break;
                     } else {
-                        tokens.push(Token::new(kind, Position::new(line, column)));
-                        stacking_token = None;
+                        tokens.push(Token::new(kind, Position::new(line, column - 1)));
                     }
                 }
             }
         // Otherwise, we have a continuation of the last token
         } else {
-            stacking_token = stacking_token.map(|mut kind| {
-                increase_kind(&mut kind, 1);
-                kind
-            });
+            if let Some((kind, _)) = stacking_token.as_mut() {
+                increase_kind(kind, 1);
+            }
         }
 
         if column == width {
@@ -196,15 +465,341 @@ pub fn read(path: &str) -> Result<Vec<Token>, ReadError> {
     }
 
     // If we have a stacking token, push it
-    if let Some(last_kind) = stacking_token {
-        let count = kind_count(&last_kind);
-        tokens.push(Token::new(
-            last_kind,
-            Position::new(line, column - count - 1),
-        ));
+    if let Some((last_kind, position)) = stacking_token {
+        tokens.push(Token::new(last_kind, position));
     }
 
-    Ok(tokens)
+    tokens.truncate(limit);
+    tokens
+}
+
+/// Decode position encoded tokens from `pixels` beginning at `start`,
+/// stopping after `EOF`, `limit` tokens or at the first block that isn't
+/// valid
+fn decode_positions(
+    pixels: &[Rgba<u8>],
+    start: usize,
+    width: u32,
+    first_line: u32,
+    limit: usize,
+) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut stacking_token: Option<(TokenKind, Position)> = None;
+
+    for (block_index, block) in pixels[start..].chunks_exact(POSITION_BLOCK).enumerate() {
+        if tokens.len() >= limit {
+            break;
+        }
+        let offset = (start + block_index * POSITION_BLOCK) as u32;
+        let position = Position::new(first_line + offset / width, offset % width);
+
+        let index = match read_block(block) {
+            Some(Some(index)) => index,
+            // An even block continues the last token
+            Some(None) => match stacking_token.as_mut() {
+                Some((kind, _)) => {
+                    increase_kind(kind, 1);
+                    continue;
+                }
+                None => break,
+            },
+            None => break,
+        };
+
+        if let Some((last_kind, position)) = stacking_token.take() {
+            tokens.push(Token::new(last_kind, position));
+        }
+
+        let mut kind =
+            distance_to_kind(MINIMUM_PIXEL_DISTANCE + index as u8).expect("corresponding kind");
+        match kind {
+            TokenKind::Increment(_)
+            | TokenKind::Decrement(_)
+            | TokenKind::Right(_)
+            | TokenKind::Left(_) => {
+                increase_kind(&mut kind, 1);
+                stacking_token = Some((kind, position));
+            }
+            TokenKind::EOF => {
+                tokens.push(Token::new(kind, position));
+                break;
+            }
+            _ => tokens.push(Token::new(kind, position)),
+        }
+    }
+
+    if let Some((last_kind, position)) = stacking_token {
+        tokens.push(Token::new(last_kind, position));
+    }
+
+    tokens.truncate(limit);
+    tokens
+}
+
+/// Decode LSB encoded tokens from `pixels` beginning at `start`, stopping
+/// after `EOF`, `limit` tokens or at the first byte that isn't a token
+fn decode_lsb(
+    pixels: &[Rgba<u8>],
+    start: usize,
+    width: u32,
+    first_line: u32,
+    mode: ColorMode,
+    limit: usize,
+) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    let count = (pixels.len() - start) / mode.pixels_per_byte();
+    let bytes = extract_bytes(&pixels[start..], count, mode).unwrap_or_default();
+    let mut rest = bytes.as_slice();
+
+    while let Some((&index, remaining)) = rest.split_first() {
+        if tokens.len() >= limit {
+            break;
+        }
+        let offset = (start + (bytes.len() - rest.len()) * mode.pixels_per_byte()) as u32;
+        let position = Position::new(first_line + offset / width, offset % width);
+        rest = remaining;
+
+        let mut kind = match distance_to_kind(MINIMUM_PIXEL_DISTANCE.saturating_add(index)) {
+            Some(kind) => kind,
+            None => break,
+        };
+        match kind {
+            TokenKind::Increment(_)
+            | TokenKind::Decrement(_)
+            | TokenKind::Right(_)
+            | TokenKind::Left(_) => match binary::read_varint(&mut rest) {
+                Some(amount) => increase_kind(&mut kind, amount),
+                None => break,
+            },
+            TokenKind::EOF => {
+                tokens.push(Token::new(kind, position));
+                break;
+            }
+            _ => {}
+        }
+        tokens.push(Token::new(kind, position));
+    }
+
+    tokens
+}
+
+/// Read the source stored as bytes after the header, prefixed by its
+/// length, and scan it back into tokens. The bytes are compressed and or
+/// encrypted as the header says.
+fn read_stored_source(
+    pixels: &[Rgba<u8>],
+    start: usize,
+    mode: ColorMode,
+    header: &Header,
+    password: Option<&str>,
+) -> Result<Vec<Token>, ReadError> {
+    let key = match (&header.encryption, password) {
+        (None, _) => None,
+        (Some(_), None) => return Err(ReadError::PasswordRequired),
+        (Some(encryption), Some(password)) => {
+            Some(Key::recover(password, encryption).ok_or(ReadError::WrongPassword)?)
+        }
+    };
+
+    let pixels = &pixels[start..];
+    let length = extract_bytes(pixels, 4, mode).ok_or(ReadError::CorruptProgram)?;
+    let length = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize;
+    let mut data = extract_bytes(pixels, 4 + length, mode).ok_or(ReadError::CorruptProgram)?;
+    data.drain(..4);
+
+    if let Some(key) = key {
+        key.apply(&mut data);
+    }
+    let source = if header.compressed {
+        compress::decompress(&data).ok_or(ReadError::CorruptProgram)?
+    } else {
+        data
+    };
+    let source = String::from_utf8(source).map_err(|_| ReadError::CorruptProgram)?;
+    Lexer::new(source)
+        .scan_tokens()
+        .map_err(|_| ReadError::CorruptProgram)
+}
+
+/// Decode every frame of the image at `path`, only PNGs can be animated
+fn read_frames(path: &str) -> Result<(Vec<RgbaImage>, ColorMode), ReadError> {
+    if is_png(path) {
+        let file = File::open(path).map_err(|_| ReadError::FileNotFound)?;
+        return decode_frames(BufReader::new(file));
+    }
+
+    let img = ImageReader::open(path)
+        .map_err(|_| ReadError::FileNotFound)?
+        .decode()
+        .map_err(|_| ReadError::DecodeFailed)?;
+    let mode = ColorMode::of(img.color());
+    let mut frame = img.to_rgba8();
+    for pixel in frame.pixels_mut() {
+        mode.expand(std::slice::from_mut(pixel));
+    }
+
+    Ok((vec![frame], mode))
+}
+
+/// Decode every frame of a PNG, animated PNGs have more than one
+fn decode_frames<R: Read>(reader: R) -> Result<(Vec<RgbaImage>, ColorMode), ReadError> {
+    let decoder = PngDecoder::new(reader).map_err(|_| ReadError::DecodeFailed)?;
+    let mode = ColorMode::of(decoder.color_type());
+
+    let mut frames = if decoder.is_apng() {
+        let frames = decoder
+            .apng()
+            .into_frames()
+            .collect_frames()
+            .map_err(|_| ReadError::DecodeFailed)?;
+        frames
+            .into_iter()
+            .map(|frame| frame.into_buffer())
+            .collect()
+    } else {
+        let img = DynamicImage::from_decoder(decoder).map_err(|_| ReadError::DecodeFailed)?;
+        vec![img.to_rgba8()]
+    };
+
+    for frame in frames.iter_mut() {
+        for pixel in frame.pixels_mut() {
+            mode.expand(std::slice::from_mut(pixel));
+        }
+    }
+
+    Ok((frames, mode))
+}
+
+/// Read the program from the image, skipping the first `offset` pixels of
+/// every frame. With `rows`, only that range of scanlines is decoded and
+/// the offset counts from its first row.
+///
+/// Decoding only starts after the header, natural pixels of an image that
+/// has none would decode into tokens too. `headerless` reads such images
+/// anyway, for programs written before the header existed.
+pub fn read(
+    path: &str,
+    offset: usize,
+    rows: Option<Range<u32>>,
+    password: Option<&str>,
+    headerless: bool,
+) -> Result<(Option<Header>, Vec<Token>), ReadError> {
+    if is_lossy(path) {
+        return Err(ReadError::LossyFormat);
+    }
+    if !is_lossless(path) {
+        return Err(ReadError::InvalidFileExtension);
+    }
+    let (frames, mode) = read_frames(path)?;
+    decode_program(frames, mode, offset, rows, password, headerless)
+}
+
+/// [`read`] for PNG bytes already in memory, such as piped through stdin
+pub fn read_bytes(
+    bytes: &[u8],
+    offset: usize,
+    rows: Option<Range<u32>>,
+    password: Option<&str>,
+    headerless: bool,
+) -> Result<(Option<Header>, Vec<Token>), ReadError> {
+    let (frames, mode) = decode_frames(bytes)?;
+    decode_program(frames, mode, offset, rows, password, headerless)
+}
+
+fn decode_program(
+    frames: Vec<RgbaImage>,
+    mode: ColorMode,
+    offset: usize,
+    rows: Option<Range<u32>>,
+    password: Option<&str>,
+    headerless: bool,
+) -> Result<(Option<Header>, Vec<Token>), ReadError> {
+    let mut tokens = Vec::new();
+    let mut header: Option<Header> = None;
+
+    let frame_count = frames.len();
+    for (index, frame) in frames.into_iter().enumerate() {
+        let (width, height) = frame.dimensions();
+        let rows = rows.clone().unwrap_or(0..height);
+        let end = rows.end.min(height);
+        let first_row = rows.start.min(end);
+        let pixels: Vec<Rgba<u8>> = frame
+            .rows()
+            .skip(first_row as usize)
+            .take((end - first_row) as usize)
+            .flatten()
+            .copied()
+            .collect();
+
+        if offset >= pixels.len() {
+            return Err(ReadError::NoProgramFound);
+        }
+
+        // Only the first frame carries the header
+        let mut start = offset;
+        if index == 0 {
+            match read_header(&pixels[offset..], mode)? {
+                Some((decoded, length)) => {
+                    start += length;
+                    if decoded.compressed || decoded.encryption.is_some() {
+                        let tokens = read_stored_source(&pixels, start, mode, &decoded, password)?;
+                        return Ok((Some(decoded), tokens));
+                    }
+                    header = Some(decoded);
+                }
+                None if !headerless => return Err(ReadError::NoHeader),
+                None => {}
+            }
+        }
+
+        let first_line = index as u32 * height + first_row;
+        let encoding = header.as_ref().map(|header| header.encoding);
+        // Tokens left per the header, frames but the last also end in an `EOF`
+        let limit = match header.as_ref().and_then(|header| header.token_count) {
+            Some(count) if index + 1 < frame_count => {
+                (count as usize).saturating_sub(tokens.len()) + 1
+            }
+            Some(count) => (count as usize).saturating_sub(tokens.len()),
+            None => usize::MAX,
+        };
+        let mut frame_tokens = match encoding.unwrap_or_default() {
+            Encoding::Delta => decode_tokens(&pixels, start, width, first_line, limit),
+            Encoding::Position => decode_positions(&pixels, start, width, first_line, limit),
+            Encoding::Lsb => decode_lsb(&pixels, start, width, first_line, mode, limit),
+        };
+
+        // Every frame but the last is terminated by an `EOF` of its own
+        if index + 1 < frame_count
+            && matches!(frame_tokens.last().map(Token::kind), Some(TokenKind::EOF))
+        {
+            frame_tokens.pop();
+        }
+        tokens.append(&mut frame_tokens);
+    }
+
+    if tokens.is_empty() {
+        return Err(ReadError::NoProgramFound);
+    }
+
+    Ok((header, tokens))
+}
+
+/// Sample `size` bytes from evenly spaced pixels of the first frame, each
+/// the XOR of the pixel's channels. The same image always gives the same
+/// bytes.
+pub fn seed(path: &str, size: usize) -> Result<Vec<u8>, ReadError> {
+    let (frames, _) = read_frames(path)?;
+    let pixels: Vec<&Rgba<u8>> = frames[0].pixels().collect();
+
+    let seed = (0..size)
+        .map(|cell| {
+            let pixel = pixels[cell * pixels.len() / size];
+            pixel.0.iter().fold(0, |byte, channel| byte ^ channel)
+        })
+        .collect();
+
+    Ok(seed)
 }
 
 #[inline]
@@ -238,81 +833,492 @@ fn encoded_pixel(distance: u8, values: &[u8; 4]) -> [u8; 4] {
     [r, g, b, a]
 }
 
-pub fn write(input_path: &str, output_path: &str, tokens: Vec<Token>) -> Result<(), WriteError> {
-    if !input_path.ends_with(".png") || !output_path.ends_with(".png") {
-        return Err(WriteError::InvalidFileExtension);
+/// Nudge the pixel at `index` until it forms a token distance with neither
+/// of its neighbours. Only red changes for RGBA, which already breaks the
+/// equal change across channels, grayscale moves every channel together.
+fn break_distances(pixels: &mut [Rgba<u8>], index: usize, mode: ColorMode) {
+    let forms_token = |pixels: &[Rgba<u8>]| {
+        let pixel = &pixels[index];
+        let previous = index
+            .checked_sub(1)
+            .is_some_and(|previous| pixel_distance(&pixels[previous], pixel).is_some());
+        let next = pixels
+            .get(index + 1)
+            .is_some_and(|next| pixel_distance(pixel, next).is_some());
+        previous || next
+    };
+
+    // Step away from the nearer bound so the value never wraps, only a few
+    // values can form a token with either neighbour so this ends quickly
+    let down = pixels[index].0[0] > u8::MAX / 2;
+    while forms_token(pixels) {
+        let pixel = &mut pixels[index];
+        let value = if down { pixel.0[0] - 1 } else { pixel.0[0] + 1 };
+        match mode {
+            ColorMode::Rgba => pixel.0[0] = value,
+            ColorMode::Luma => pixel.0 = [value; 4],
+        }
+    }
+}
+
+/// Number of empty pixels following a token
+#[inline]
+fn kind_skip(kind: &TokenKind) -> usize {
+    match kind {
+        TokenKind::Increment(amount)
+        | TokenKind::Decrement(amount)
+        | TokenKind::Right(amount)
+        | TokenKind::Left(amount) => *amount,
+        _ => 0,
+    }
+}
+
+/// Encode as many whole tokens as fit into `pixels` beginning at `start`,
+/// returning the index of the pixel the next token would start from.
+/// With `reserve_eof`, room for a trailing `EOF` token is always left.
+fn encode_tokens<I: Iterator<Item = Token>>(
+    pixels: &mut [Rgba<u8>],
+    start: usize,
+    tokens: &mut Peekable<I>,
+    reserve_eof: bool,
+    mode: ColorMode,
+) -> usize {
+    let mut i = start;
+
+    while let Some(token) = tokens.peek() {
+        let amount = kind_skip(token.kind());
+        let reserved = usize::from(reserve_eof && !matches!(token.kind(), TokenKind::EOF));
+        if i + 1 + amount + reserved >= pixels.len() {
+            break;
+        }
+
+        let distance = kind_to_distance(token.kind());
+        pixels[i + 1].0 = encoded_pixel(distance, &pixels[i].0);
+        tokens.next();
+
+        for _ in 0..amount {
+            i += 1;
+
+            if pixel_distance(&pixels[i], &pixels[i + 1]).is_some() {
+                // We don't want this, the empty pixel would read as a token
+                break_distances(pixels, i + 1, mode);
+            }
+        }
+
+        i += 1;
+    }
+
+    i
+}
+
+/// Position encoding counterpart of [`encode_tokens`], a stacking token
+/// takes one block per unit of its amount
+fn encode_positions<I: Iterator<Item = Token>>(
+    pixels: &mut [Rgba<u8>],
+    start: usize,
+    tokens: &mut Peekable<I>,
+    reserve_eof: bool,
+    mode: ColorMode,
+) -> usize {
+    let mut i = start;
+
+    while let Some(token) = tokens.peek() {
+        let blocks = kind_skip(token.kind()).max(1);
+        let reserved = usize::from(reserve_eof && !matches!(token.kind(), TokenKind::EOF));
+        if i + (blocks + reserved) * POSITION_BLOCK > pixels.len() {
+            break;
+        }
+
+        let index = kind_to_index(token.kind());
+        mark_block(&mut pixels[i..i + POSITION_BLOCK], Some(index), mode);
+        tokens.next();
+
+        for _ in 1..blocks {
+            i += POSITION_BLOCK;
+            mark_block(&mut pixels[i..i + POSITION_BLOCK], None, mode);
+        }
+
+        i += POSITION_BLOCK;
+    }
+
+    i
+}
+
+/// LSB encoding counterpart of [`encode_tokens`]
+fn encode_lsb<I: Iterator<Item = Token>>(
+    pixels: &mut [Rgba<u8>],
+    start: usize,
+    tokens: &mut Peekable<I>,
+    reserve_eof: bool,
+    mode: ColorMode,
+) -> usize {
+    let mut i = start;
+
+    while let Some(token) = tokens.peek() {
+        let bytes = lsb_bytes(token.kind());
+        let reserved = usize::from(reserve_eof && !matches!(token.kind(), TokenKind::EOF));
+        if i + (bytes.len() + reserved) * mode.pixels_per_byte() > pixels.len() {
+            break;
+        }
+
+        i += embed_bytes(&mut pixels[i..], &bytes, mode).expect("checked to fit");
+        tokens.next();
     }
-    let mut tokens = tokens.into_iter();
 
+    i
+}
+
+/// Pixels taken by the tokens when encoded from the start of the program
+fn pixels_needed(encoding: Encoding, tokens: &[Token], mode: ColorMode) -> usize {
+    match encoding {
+        // Tokens are changes between pixels, so the first one needs a base
+        Encoding::Delta => {
+            1 + tokens
+                .iter()
+                .map(|token| 1 + kind_skip(token.kind()))
+                .sum::<usize>()
+        }
+        Encoding::Position => tokens
+            .iter()
+            .map(|token| kind_skip(token.kind()).max(1) * POSITION_BLOCK)
+            .sum(),
+        Encoding::Lsb => tokens
+            .iter()
+            .map(|token| lsb_bytes(token.kind()).len() * mode.pixels_per_byte())
+            .sum(),
+    }
+}
+
+fn encode<I: Iterator<Item = Token>>(
+    encoding: Encoding,
+    cover: &Cover,
+    pixels: &mut [Rgba<u8>],
+    start: usize,
+    tokens: &mut Peekable<I>,
+    reserve_eof: bool,
+) -> usize {
+    match encoding {
+        Encoding::Delta => encode_tokens(pixels, start, tokens, reserve_eof, cover.mode),
+        Encoding::Position => encode_positions(pixels, start, tokens, reserve_eof, cover.mode),
+        Encoding::Lsb => encode_lsb(pixels, start, tokens, reserve_eof, cover.mode),
+    }
+}
+
+/// Cover image with the header already embedded
+struct Cover {
+    pixels: Vec<Rgba<u8>>,
+    width: u32,
+    height: u32,
+    /// Index of the first pixel after the header
+    program_start: usize,
+    mode: ColorMode,
+}
+
+impl Cover {
+    /// Pixel data in the layout of the cover, for the png encoder
+    fn bytes(&self, pixels: &[Rgba<u8>]) -> Vec<u8> {
+        match self.mode {
+            ColorMode::Rgba => pixels.iter().flat_map(|pixel| pixel.0).collect(),
+            ColorMode::Luma => pixels.iter().map(|pixel| pixel.0[0]).collect(),
+        }
+    }
+}
+
+/// Load the cover and embed the header. Grayscale covers stay grayscale
+/// only if the format of `output_path` reads them back that way.
+fn load_cover(
+    input_path: &str,
+    output_path: &str,
+    header: &Header,
+    offset: usize,
+) -> Result<Cover, WriteError> {
     let img = ImageReader::open(input_path)
         .map_err(|_| WriteError::FileNotFound)?
         .decode()
-        .map_err(|_| WriteError::DecodeFailed)?
-        .to_rgba8();
+        .map_err(|_| WriteError::DecodeFailed)?;
+    let mode = match ColorMode::of(img.color()) {
+        // Grayscale BMPs are palettes that decode as RGB
+        ColorMode::Luma if extension(output_path) == "bmp" => ColorMode::Rgba,
+        mode => mode,
+    };
+    let img = img.to_rgba8();
 
     let (width, height) = img.dimensions();
-    let mut pixels: Vec<Rgba<u8>> = img.pixels().map(|pixel| pixel.clone()).collect();
+    let mut pixels: Vec<Rgba<u8>> = img.pixels().copied().collect();
+    mode.expand(&mut pixels);
 
-    let mut i = 0;
-    while i < pixels.len() {
-        let pixel = pixels[i];
-        let next_pixel = match pixels.get_mut(i + 1) {
-            Some(pixel) => pixel,
-            None => break,
-        };
+    let header = header.encode().map_err(WriteError::InvalidHeader)?;
+    let length = match pixels.get_mut(offset..) {
+        Some(pixels) => embed_bytes(pixels, &header, mode),
+        None => None,
+    };
+    let program_start = match length {
+        Some(length) if offset + length + 1 < pixels.len() => offset + length,
+        _ => return Err(WriteError::ImageTooSmall),
+    };
 
-        let token = match tokens.next() {
-            Some(token) => token,
-            None => break,
-        };
+    Ok(Cover {
+        pixels,
+        width,
+        height,
+        program_start,
+        mode,
+    })
+}
 
-        match token.kind() {
-            &TokenKind::Increment(amount)
-            | &TokenKind::Decrement(amount)
-            | &TokenKind::Left(amount)
-            | &TokenKind::Right(amount) => {
-                let distance = kind_to_distance(token.kind());
-                let encoded = encoded_pixel(distance, &pixel.0);
+/// Room a cover image has for a program after its header
+#[derive(Debug, Clone, Copy)]
+pub struct Capacity {
+    /// Pixels left for the tokens
+    pub pixels: usize,
+    /// Most tokens that fit, when no token stacks
+    pub tokens: usize,
+    /// Commands that fit however they stack, a stacking command on its own
+    /// takes a token and an empty pixel
+    pub commands: usize,
+}
 
-                next_pixel.0 = encoded;
+pub fn capacity(input_path: &str, header: &Header, offset: usize) -> Result<Capacity, WriteError> {
+    check_write_format(input_path)?;
+    // Writing always stores the token count, the largest one keeps the
+    // estimate on the safe side
+    let header = &Header {
+        token_count: Some(u32::MAX),
+        ..header.clone()
+    };
+    let cover = load_cover(input_path, input_path, header, offset)?;
+    let pixels = cover.pixels.len() - cover.program_start;
 
-                for _ in 0..amount {
-                    if i + 1 >= pixels.len() {
-                        break;
-                    }
-                    let pixel = pixels[i + 1];
+    // Every program ends in an `EOF` token, which takes the room of one
+    // more token
+    let (tokens, commands) = match header.encoding {
+        // The first pixel is only the base of the first change
+        Encoding::Delta => {
+            let tokens = pixels.saturating_sub(2);
+            (tokens, tokens / 2)
+        }
+        Encoding::Position => {
+            let blocks = (pixels / POSITION_BLOCK).saturating_sub(1);
+            (blocks, blocks)
+        }
+        // A stacking command takes a byte for its kind and one for its amount
+        Encoding::Lsb => {
+            let bytes = (pixels / cover.mode.pixels_per_byte()).saturating_sub(1);
+            (bytes, bytes / 2)
+        }
+    };
 
-                    let next_pixel = match pixels.get_mut(i + 2) {
-                        Some(pixel) => pixel,
-                        None => break,
-                    };
+    Ok(Capacity {
+        pixels,
+        tokens,
+        commands,
+    })
+}
 
-                    if let Some(_) = pixel_distance(&pixel, &next_pixel) {
-                        // We don't want this, modify first pixel so that change is not the same
-                        let r = next_pixel.0[0];
-                        next_pixel.0[0] = if r > u8::MAX / 2 { r - 1 } else { r + 1 };
-                    }
+/// Write the program into a copy of the cover. With a password, the
+/// source is stored encrypted as bytes, see [`Header::encryption`].
+pub fn write(
+    input_path: &str,
+    output_path: &str,
+    tokens: Vec<Token>,
+    header: &Header,
+    offset: usize,
+    password: Option<&str>,
+) -> Result<(), WriteError> {
+    check_write_format(input_path)?;
+    check_write_format(output_path)?;
+    let key = password
+        .map(Key::generate)
+        .transpose()
+        .map_err(|_| WriteError::RandomUnavailable)?;
+    let mut header = Header {
+        encryption: key.as_ref().map(|key| key.encryption.clone()),
+        token_count: u32::try_from(tokens.len()).ok(),
+        ..header.clone()
+    };
+    let mut cover = load_cover(input_path, output_path, &header, offset)?;
+    let needed = pixels_needed(header.encoding, &tokens, cover.mode);
+
+    // Source bytes stored instead of tokens. Compression is dropped when it
+    // doesn't save any pixels over storing the program without it.
+    let mut stored = None;
+    if header.compressed || key.is_some() {
+        let source = lexer::render(&tokens, &[], lexer::DEFAULT_COMMENT_CHARS[0]).into_bytes();
+        let pixels_per_byte = cover.mode.pixels_per_byte();
+        let uncompressed = match key {
+            Some(_) => (4 + source.len()) * pixels_per_byte,
+            None => needed,
+        };
 
-                    i += 1;
+        let compressed = header.compressed.then(|| compress::compress(&source));
+        match compressed {
+            Some(data) if (4 + data.len()) * pixels_per_byte < uncompressed => stored = Some(data),
+            _ => {
+                if header.compressed {
+                    header.compressed = false;
+                    cover = load_cover(input_path, output_path, &header, offset)?;
                 }
+                stored = key.is_some().then_some(source);
             }
-            TokenKind::LoopStart
-            | TokenKind::LoopEnd
-            | TokenKind::PutChar
-            | TokenKind::ReadChar
-            | TokenKind::EOF => {
-                let distance = kind_to_distance(token.kind());
-                next_pixel.0 = encoded_pixel(distance, &pixel.0);
+        }
+    }
+    let mut tokens = tokens.into_iter().peekable();
+
+    let mut pixels = cover.pixels.clone();
+    let available = pixels.len() - cover.program_start;
+    if let Some(mut data) = stored {
+        if let Some(key) = &key {
+            key.apply(&mut data);
+        }
+        let length = u32::try_from(data.len()).map_err(|_| WriteError::ImageTooSmall)?;
+
+        let bytes: Vec<u8> = length.to_le_bytes().into_iter().chain(data).collect();
+        embed_bytes(&mut pixels[cover.program_start..], &bytes, cover.mode).ok_or(
+            WriteError::ProgramTooLarge {
+                needed: bytes.len() * cover.mode.pixels_per_byte(),
+                available,
+            },
+        )?;
+    } else {
+        encode(
+            header.encoding,
+            &cover,
+            &mut pixels,
+            cover.program_start,
+            &mut tokens,
+            false,
+        );
+        if tokens.peek().is_some() {
+            return Err(WriteError::ProgramTooLarge { needed, available });
+        }
+    }
+
+    let (width, height) = (cover.width, cover.height);
+    let saved = match cover.mode {
+        ColorMode::Rgba => {
+            ImageBuffer::from_fn(width, height, |x, y| pixels[(y * width + x) as usize])
+                .save(output_path)
+        }
+        ColorMode::Luma => ImageBuffer::from_fn(width, height, |x, y| {
+            Luma([pixels[(y * width + x) as usize].0[0]])
+        })
+        .save(output_path),
+    };
+    saved.map_err(|_| WriteError::SaveFailed)?;
+
+    Ok(())
+}
+
+/// Save `bytes` as an image `width` pixels wide, a gray pixel per byte or
+/// an RGB pixel per three bytes with `rgb`. The last row is padded with
+/// black. Returns the height of the image.
+pub fn render_bytes(path: &str, bytes: &[u8], width: u32, rgb: bool) -> Result<u32, WriteError> {
+    let channels = if rgb { 3 } else { 1 };
+    let row = width as usize * channels;
+    let height = bytes.len().div_ceil(row).max(1);
+    let height = u32::try_from(height).map_err(|_| WriteError::SaveFailed)?;
+
+    let mut data = bytes.to_vec();
+    data.resize(height as usize * row, 0);
+
+    let saved = if rgb {
+        ImageBuffer::<image::Rgb<u8>, _>::from_raw(width, height, data).map(|img| img.save(path))
+    } else {
+        ImageBuffer::<Luma<u8>, _>::from_raw(width, height, data).map(|img| img.save(path))
+    };
+    match saved {
+        Some(Ok(())) => Ok(height),
+        _ => Err(WriteError::SaveFailed),
+    }
+}
+
+/// Write the program across as many frames of an animated PNG as it needs,
+/// every frame being a copy of the cover image. Returns the frame count.
+pub fn write_animated(
+    input_path: &str,
+    output_path: &str,
+    tokens: Vec<Token>,
+    header: &Header,
+    offset: usize,
+) -> Result<usize, WriteError> {
+    check_write_format(input_path)?;
+    if !is_png(output_path) {
+        return Err(WriteError::AnimatedFormat);
+    }
+    if header.compressed {
+        return Err(WriteError::AnimatedCompression);
+    }
+    let header = &Header {
+        token_count: u32::try_from(tokens.len()).ok(),
+        ..header.clone()
+    };
+    let mut tokens = tokens.into_iter().peekable();
+
+    let cover = load_cover(input_path, output_path, header, offset)?;
+    let mut frames: Vec<Vec<Rgba<u8>>> = Vec::new();
+
+    while tokens.peek().is_some() {
+        let mut frame = cover.pixels.clone();
+        let start = if frames.is_empty() {
+            cover.program_start
+        } else {
+            offset
+        };
+        let end = encode(
+            header.encoding,
+            &cover,
+            &mut frame,
+            start,
+            &mut tokens,
+            true,
+        );
+
+        if tokens.peek().is_some() {
+            if end == start {
+                return Err(WriteError::ImageTooSmall);
+            }
+            // Terminate the frame so the last run doesn't continue into it
+            match header.encoding {
+                Encoding::Delta => {
+                    let distance = kind_to_distance(&TokenKind::EOF);
+                    frame[end + 1].0 = encoded_pixel(distance, &frame[end].0);
+                }
+                Encoding::Position => {
+                    let index = kind_to_index(&TokenKind::EOF);
+                    mark_block(
+                        &mut frame[end..end + POSITION_BLOCK],
+                        Some(index),
+                        cover.mode,
+                    );
+                }
+                Encoding::Lsb => {
+                    let bytes = lsb_bytes(&TokenKind::EOF);
+                    embed_bytes(&mut frame[end..], &bytes, cover.mode);
+                }
             }
         }
 
-        i += 1;
+        frames.push(frame);
     }
 
-    let buffer = ImageBuffer::from_fn(width, height, |x, y| pixels[(y * width + x) as usize]);
-    buffer
-        .save(output_path)
+    let file = File::create(output_path).map_err(|_| WriteError::SaveFailed)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), cover.width, cover.height);
+    encoder.set_color(match cover.mode {
+        ColorMode::Rgba => png::ColorType::Rgba,
+        ColorMode::Luma => png::ColorType::Grayscale,
+    });
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(frames.len() as u32, 0)
         .map_err(|_| WriteError::SaveFailed)?;
 
-    Ok(())
+    let mut writer = encoder.write_header().map_err(|_| WriteError::SaveFailed)?;
+    for frame in &frames {
+        writer
+            .write_image_data(&cover.bytes(frame))
+            .map_err(|_| WriteError::SaveFailed)?;
+    }
+    writer.finish().map_err(|_| WriteError::SaveFailed)?;
+
+    Ok(frames.len())
 }
@@ -1,26 +1,598 @@
+use std::{cell::RefCell, collections::VecDeque, fmt::Display, io::Write, ops::Range, rc::Rc};
+
+use crate::bytecode::{Bytecode, Instruction};
+
 #[derive(Debug)]
+pub enum RuntimeError {
+    PointerUnderflow,
+    PointerOverflow,
+    OutputFailed,
+    StepLimitExceeded(u64),
+    LoopIterationLimitExceeded(u64),
+    LikelyInfiniteLoop,
+    MemoryLimitExceeded(usize),
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::PointerUnderflow => {
+                formatter.write_str("pointer moved left of the first cell")
+            }
+            RuntimeError::PointerOverflow => {
+                formatter.write_str("pointer moved right of the last cell")
+            }
+            RuntimeError::OutputFailed => formatter.write_str("failed to write output"),
+            RuntimeError::StepLimitExceeded(limit) => formatter.write_fmt(format_args!(
+                "step limit of {} exceeded, the program may loop forever",
+                limit
+            )),
+            RuntimeError::LoopIterationLimitExceeded(limit) => {
+                formatter.write_fmt(format_args!("a loop ran more than {} iterations", limit))
+            }
+            RuntimeError::LikelyInfiniteLoop => formatter.write_str(
+                "loop went a full cycle without changing its cell or doing any I/O, \
+                 it likely never ends",
+            ),
+            RuntimeError::MemoryLimitExceeded(limit) => formatter.write_fmt(format_args!(
+                "tape would grow past its limit of {} cells",
+                limit
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Hook invoked on every read and write of a cell by the program
+pub trait MemoryLogger: std::fmt::Debug {
+    fn log(&mut self, access: Access, index: usize, value: u8);
+}
+
+/// The most recent pointer positions of a run, one per cell moved
+#[derive(Debug, Clone)]
+pub struct PointerHistory {
+    positions: VecDeque<usize>,
+    capacity: usize,
+    /// Positions recorded in total, including the dropped ones
+    recorded: u64,
+}
+
+impl PointerHistory {
+    /// History keeping the last `capacity` positions, starting at cell 0
+    pub fn new(capacity: usize) -> Self {
+        let mut history = Self {
+            positions: VecDeque::with_capacity(capacity),
+            capacity,
+            recorded: 0,
+        };
+        history.push(0);
+        history
+    }
+
+    fn push(&mut self, position: usize) {
+        if self.positions.len() == self.capacity {
+            self.positions.pop_front();
+        }
+        self.positions.push_back(position);
+        self.recorded += 1;
+    }
+
+    fn clear(&mut self) {
+        self.positions.clear();
+        self.recorded = 0;
+        self.push(0);
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.positions.iter().copied()
+    }
+
+    pub fn recorded(&self) -> u64 {
+        self.recorded
+    }
+
+    /// One bar per position, scaled between the lowest and highest cell
+    pub fn sparkline(&self) -> String {
+        const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+        let low = self.positions().min().unwrap_or(0);
+        let high = self.positions().max().unwrap_or(0);
+        let range = (high - low).max(1);
+        self.positions()
+            .map(|position| BARS[(position - low) * (BARS.len() - 1) / range])
+            .collect()
+    }
+}
+
+/// Writer that receives output as it is produced, e.g. stdout
+pub trait OutputStream: Write + std::fmt::Debug {}
+
+impl<T: Write + std::fmt::Debug> OutputStream for T {}
+
+/// What happens when the pointer moves past either end of the tape
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PointerMode {
+    /// Stop at the first or last cell
+    #[default]
+    Saturate,
+    /// Continue from the other end of the tape
+    Wrap,
+    /// Stop the program with a runtime error
+    Error,
+}
+
+#[derive(Debug, Clone)]
 pub struct State {
     pub memory: Vec<u8>,
-    pub pointer: usize,
+    /// Only moved through methods that keep it on the tape
+    pointer: usize,
     pub input: Vec<u8>,
+    /// Index of the next input byte `,` reads
+    pub input_position: usize,
     pub output: Vec<u8>,
+    /// Number of commands executed so far
+    pub steps: u64,
+    /// Stop the program once it executes more commands than this
+    pub max_steps: Option<u64>,
+    /// Stop the program once a single run of a loop iterates more than this
+    pub max_loop_iterations: Option<u64>,
+    /// Steps without I/O after which a loop cycle that leaves the pointer
+    /// and its cell unchanged is treated as infinite
+    pub loop_guard: Option<u64>,
+    /// Step count at the last `.` or `,`
+    pub last_io_step: u64,
+    /// Rightmost cell the pointer has reached
+    pub high_water: usize,
+    /// Write cells as decimal numbers instead of characters
+    pub numeric_output: bool,
+    /// Copy bytes read by `,` to the output, like a terminal echo
+    pub echo_input: bool,
+    pub pointer_mode: PointerMode,
+    /// Grow the tape when the pointer moves past its end, up to this many
+    /// cells. `None` keeps the tape at a fixed size.
+    pub growth_limit: Option<usize>,
+    /// Records where the pointer went when set
+    pub pointer_history: Option<PointerHistory>,
+    /// Cells the tape starts with instead of zeroes, restored on reset
+    pub initial_tape: Vec<u8>,
+    pub memory_logger: Option<Rc<RefCell<dyn MemoryLogger>>>,
+    /// Also write output here as it is produced, flushing every write
+    pub output_stream: Option<Rc<RefCell<dyn OutputStream>>>,
 }
 
-const MEMORY_SIZE: usize = 256;
+/// Steps without I/O before the loop guard starts checking loops
+pub const LOOP_GUARD_STEPS: u64 = 10_000;
+
+/// Default number of cells on the tape
+pub const MEMORY_SIZE: usize = 256;
+
+/// Cells a growable tape starts with
+const GROWABLE_START_SIZE: usize = 64;
+
+/// Default cap of a growable tape, 16 MiB of cells
+pub const GROWTH_LIMIT: usize = 16 * 1024 * 1024;
 
 impl State {
     pub fn new(input: Vec<u8>) -> Self {
+        Self::with_memory_size(input, MEMORY_SIZE)
+    }
+
+    /// State with a tape of `size` cells. The pointer mode decides what
+    /// happens when the pointer moves past either end of it.
+    pub fn with_memory_size(input: Vec<u8>, size: usize) -> Self {
+        assert!(size > 0, "tape needs at least one cell");
         Self {
-            memory: vec![0; MEMORY_SIZE],
+            memory: vec![0; size],
             pointer: 0,
             input,
+            input_position: 0,
             output: Vec::new(),
+            steps: 0,
+            max_steps: None,
+            max_loop_iterations: None,
+            loop_guard: None,
+            last_io_step: 0,
+            high_water: 0,
+            numeric_output: false,
+            echo_input: false,
+            pointer_mode: PointerMode::default(),
+            growth_limit: None,
+            pointer_history: None,
+            initial_tape: Vec::new(),
+            memory_logger: None,
+            output_stream: None,
+        }
+    }
+
+    /// State with a small tape that grows on demand up to [`GROWTH_LIMIT`]
+    pub fn new_growable(input: Vec<u8>) -> Self {
+        let mut state = Self::with_memory_size(input, GROWABLE_START_SIZE);
+        state.growth_limit = Some(GROWTH_LIMIT);
+        state
+    }
+
+    /// Extend the tape so that `index` is on it, doubling the size to keep
+    /// growth amortized. Does nothing past the growth limit.
+    fn grow_to(&mut self, index: usize) {
+        let Some(limit) = self.growth_limit else {
+            return;
+        };
+        if index < self.memory.len() || index >= limit {
+            return;
+        }
+        let size = (self.memory.len() * 2).max(index + 1).min(limit);
+        self.memory.resize(size, 0);
+    }
+
+    /// Count an executed command against the step limit
+    pub fn step(&mut self) -> Result<(), RuntimeError> {
+        self.step_n(1)
+    }
+
+    /// Count `count` commands at once, for loops that run without looping
+    pub fn step_n(&mut self, count: u64) -> Result<(), RuntimeError> {
+        self.steps += count;
+        match self.max_steps {
+            Some(limit) if self.steps > limit => Err(RuntimeError::StepLimitExceeded(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the iterations of the loop being run against the loop budget
+    pub fn check_loop_iterations(&self, iterations: u64) -> Result<(), RuntimeError> {
+        match self.max_loop_iterations {
+            Some(limit) if iterations > limit => {
+                Err(RuntimeError::LoopIterationLimitExceeded(limit))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Pointer and value of its cell, what the loop guard compares
+    pub fn loop_snapshot(&self) -> (usize, u8) {
+        (self.pointer, self.memory[self.pointer])
+    }
+
+    /// Heuristic for the loop guard, called after a loop cycle that started
+    /// at `before`. A cycle that changes nothing the loop condition sees
+    /// long after the last I/O is likely to repeat forever.
+    pub fn check_loop_progress(&self, before: (usize, u8)) -> Result<(), RuntimeError> {
+        match self.loop_guard {
+            Some(guard)
+                if self.steps - self.last_io_step > guard && self.loop_snapshot() == before =>
+            {
+                Err(RuntimeError::LikelyInfiniteLoop)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the current cell
+    pub fn read(&self) -> u8 {
+        let value = self.memory[self.pointer];
+        self.log(Access::Read, value);
+        value
+    }
+
+    /// Write the current cell
+    pub fn write(&mut self, value: u8) {
+        self.memory[self.pointer] = value;
+        self.log(Access::Write, value);
+    }
+
+    /// Consume the next input byte, `None` once the input is exhausted
+    pub fn read_input(&mut self) -> Option<u8> {
+        let byte = self.input.get(self.input_position).copied()?;
+        self.input_position += 1;
+        self.last_io_step = self.steps;
+        Some(byte)
+    }
+
+    /// Collect bytes in the output, teeing them to the output stream if
+    /// there is one so both see the same bytes in the same order
+    pub fn emit(&mut self, bytes: &[u8]) -> Result<(), RuntimeError> {
+        self.last_io_step = self.steps;
+        self.output.extend_from_slice(bytes);
+        match &self.output_stream {
+            Some(stream) => {
+                let mut stream = stream.borrow_mut();
+                stream
+                    .write_all(bytes)
+                    .and_then(|_| stream.flush())
+                    .map_err(|_| RuntimeError::OutputFailed)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn log(&self, access: Access, value: u8) {
+        self.log_at(access, self.pointer, value);
+    }
+
+    fn log_at(&self, access: Access, index: usize, value: u8) {
+        if let Some(logger) = &self.memory_logger {
+            logger.borrow_mut().log(access, index, value);
+        }
+    }
+
+    /// Add `value` to the cell at `index`, wrapping around
+    pub fn add_at(&mut self, index: usize, value: u8) {
+        let value = self.memory[index].wrapping_add(value);
+        self.memory[index] = value;
+        self.log_at(Access::Write, index, value);
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// Jump the pointer to `pointer`, for extensions such as debugger pokes.
+    /// A growable tape grows to reach it, anywhere else off the tape is
+    /// rejected whatever the pointer mode.
+    pub fn set_pointer(&mut self, pointer: usize) -> Result<(), RuntimeError> {
+        self.grow_to(pointer);
+        if pointer >= self.memory.len() {
+            return Err(match self.growth_limit {
+                Some(limit) => RuntimeError::MemoryLimitExceeded(limit),
+                None => RuntimeError::PointerOverflow,
+            });
+        }
+
+        self.pointer = pointer;
+        self.high_water = self.high_water.max(pointer);
+        if let Some(history) = self.pointer_history.as_mut() {
+            history.push(pointer);
+        }
+        Ok(())
+    }
+
+    /// Move the pointer right, handling the end of the tape per `pointer_mode`.
+    /// A growable tape errors instead once it can't grow to reach the cell.
+    pub fn move_right(&mut self, amount: usize) -> Result<(), RuntimeError> {
+        let from = self.pointer;
+        let target = self.pointer.checked_add(amount);
+        if let Some(index) = target {
+            self.grow_to(index);
+        }
+        if let Some(limit) = self.growth_limit {
+            if target.is_none_or(|index| index >= self.memory.len()) {
+                return Err(RuntimeError::MemoryLimitExceeded(limit));
+            }
         }
+        let last = self.memory.len() - 1;
+        self.pointer = match self.pointer.checked_add(amount) {
+            Some(pointer) if pointer <= last => pointer,
+            _ => match self.pointer_mode {
+                PointerMode::Saturate => last,
+                PointerMode::Wrap => {
+                    (self.pointer + amount % self.memory.len()) % self.memory.len()
+                }
+                PointerMode::Error => return Err(RuntimeError::PointerOverflow),
+            },
+        };
+        self.high_water = self.high_water.max(self.pointer);
+        self.record_move(from, true);
+        Ok(())
     }
+
+    /// Move the pointer left, handling the start of the tape per `pointer_mode`
+    pub fn move_left(&mut self, amount: usize) -> Result<(), RuntimeError> {
+        let from = self.pointer;
+        self.pointer = match self.pointer.checked_sub(amount) {
+            Some(pointer) => pointer,
+            None => match self.pointer_mode {
+                PointerMode::Saturate => 0,
+                PointerMode::Wrap => {
+                    let length = self.memory.len();
+                    (self.pointer + length - amount % length) % length
+                }
+                PointerMode::Error => return Err(RuntimeError::PointerUnderflow),
+            },
+        };
+        self.record_move(from, false);
+        Ok(())
+    }
+
+    /// Add every cell passed on the way from `from` to the pointer history,
+    /// a move that wrapped around is recorded as a jump
+    fn record_move(&mut self, from: usize, right: bool) {
+        let Some(history) = self.pointer_history.as_mut() else {
+            return;
+        };
+        let to = self.pointer;
+        match (right, from.cmp(&to)) {
+            (true, std::cmp::Ordering::Less) => (from + 1..=to).for_each(|p| history.push(p)),
+            (false, std::cmp::Ordering::Greater) => (to..from).rev().for_each(|p| history.push(p)),
+            _ => history.push(to),
+        }
+    }
+
+    /// Zero the tape and clear input and output for another run, keeping
+    /// configured modes and allocated buffers
+    pub fn reset_keep_capacity(&mut self) {
+        self.reset_tape();
+        self.reset_run();
+    }
+
+    /// Zero the tape and move the pointer back to the first cell, input and
+    /// output are left alone
+    pub fn reset_tape(&mut self) {
+        self.memory.fill(0);
+        self.load_initial_tape();
+        self.pointer = 0;
+        if let Some(history) = self.pointer_history.as_mut() {
+            history.clear();
+        }
+        self.high_water = 0;
+    }
+
+    /// Clear input, output and counters for another run that continues on
+    /// the same tape, like the next line of a REPL
+    pub fn reset_run(&mut self) {
+        self.input.clear();
+        self.input_position = 0;
+        self.output.clear();
+        self.steps = 0;
+        self.last_io_step = 0;
+    }
+
+    /// Copy the initial tape onto the start of memory, cells past the end
+    /// of memory are dropped
+    pub fn load_initial_tape(&mut self) {
+        let length = self.initial_tape.len().min(self.memory.len());
+        self.memory[..length].copy_from_slice(&self.initial_tape[..length]);
+    }
+
+    /// Drop a single trailing `\n` from the output, if there is one
+    pub fn strip_trailing_newline(&mut self) {
+        if self.output.last() == Some(&b'\n') {
+            self.output.pop();
+        }
+    }
+
+    /// Cells from the pointer up to the rightmost cell reached
+    pub fn tape_since_pointer(&self) -> &[u8] {
+        let end = self.high_water.max(self.pointer);
+        &self.memory[self.pointer..=end]
+    }
+
+    /// Pointer, tape and output as JSON, for post-mortem debugging
+    pub fn snapshot_json(&self) -> String {
+        let memory: Vec<String> = self.memory.iter().map(u8::to_string).collect();
+        format!(
+            "{{\"pointer\":{},\"steps\":{},\"memory\":[{}],\"output\":{}}}",
+            self.pointer,
+            self.steps,
+            memory.join(","),
+            self.output_json()
+        )
+    }
+
+    /// Output bytes as a JSON array of numbers
+    pub fn output_json(&self) -> String {
+        let bytes: Vec<String> = self.output.iter().map(u8::to_string).collect();
+        format!("[{}]", bytes.join(","))
+    }
+
+    /// Output decoded as UTF-8, replacing invalid sequences
+    pub fn output_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+/// Translate `\r\n` and lone `\r` line endings in input to `\n`
+pub fn normalize_newlines(input: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(input.len());
+    let mut bytes = input.iter().peekable();
+
+    while let Some(&byte) = bytes.next() {
+        if byte == b'\r' {
+            bytes.next_if_eq(&&b'\n');
+            normalized.push(b'\n');
+        } else {
+            normalized.push(byte);
+        }
+    }
+
+    normalized
+}
+
+/// The bytecode VM. Loop guard snapshots and cycle counts of the loops
+/// being run are kept on a stack, innermost last.
+pub(crate) fn execute(bytecode: &mut Bytecode, state: &mut State) -> Result<(), RuntimeError> {
+    let mut counter = 0;
+    let mut loops: Vec<((usize, u8), u64)> = Vec::new();
+
+    while let Some(&instruction) = bytecode.instructions.get(counter) {
+        match instruction {
+            Instruction::Operator(mut operator) => operator.interpret(state)?,
+            Instruction::LoopStart { end, index } => {
+                if state.read() == 0 {
+                    counter = end;
+                } else {
+                    state.step()?;
+                    bytecode.loops[index].1 += 1;
+                    state.check_loop_iterations(1)?;
+                    loops.push((state.loop_snapshot(), 1));
+                }
+            }
+            Instruction::SetZero { index, increment } => {
+                let value = state.read();
+                if value != 0 {
+                    // The loop body would run until the cell wraps to zero,
+                    // one step for the `[` and one for the body every cycle
+                    let cycles = if increment {
+                        256 - value as u64
+                    } else {
+                        value as u64
+                    };
+                    state.step_n(2 * cycles)?;
+                    bytecode.loops[index].1 += cycles as usize;
+                    state.check_loop_iterations(cycles)?;
+                    state.write(0);
+                }
+            }
+            Instruction::Multiply {
+                index,
+                multiply,
+                end,
+            } => {
+                let multiply = &bytecode.multiplies[multiply];
+                let pointer = state.pointer();
+                let (low, high) = multiply.reach;
+                let on_tape = pointer
+                    .checked_add_signed(low)
+                    .zip(pointer.checked_add_signed(high))
+                    .is_some_and(|(_, high)| high < state.memory.len());
+                if on_tape {
+                    let value = state.read();
+                    if value != 0 {
+                        let cycles = value as u64;
+                        state.step_n(cycles * (1 + multiply.body_length))?;
+                        bytecode.loops[index].1 += value as usize;
+                        state.check_loop_iterations(cycles)?;
+                        for target in multiply.targets.iter() {
+                            let cell = pointer.wrapping_add_signed(target.offset);
+                            state.add_at(cell, target.factor.wrapping_mul(value));
+                        }
+                        state.high_water = state.high_water.max(pointer + high as usize);
+                        state.write(0);
+                    }
+                    counter = end;
+                }
+            }
+            Instruction::LoopEnd { start } => {
+                if let Some((before, _)) = loops.last() {
+                    state.check_loop_progress(*before)?;
+                }
+                if state.read() != 0 {
+                    state.step()?;
+                    if let Instruction::LoopStart { index, .. } = bytecode.instructions[start] {
+                        bytecode.loops[index].1 += 1;
+                    }
+                    if let Some((before, cycles)) = loops.last_mut() {
+                        *cycles += 1;
+                        state.check_loop_iterations(*cycles)?;
+                        *before = state.loop_snapshot();
+                    }
+                    counter = start;
+                } else {
+                    loops.pop();
+                }
+            }
+        }
+        counter += 1;
+    }
+    Ok(())
 }
 
 pub trait Interpret {
-    fn interpret(&mut self, state: &mut State);
+    fn interpret(&mut self, state: &mut State) -> Result<(), RuntimeError>;
 }
 
 pub struct Interpreter {
@@ -34,25 +606,188 @@ impl Interpreter {
         }
     }
 
-    pub fn interpret(&mut self, program: &mut dyn Interpret) {
-        program.interpret(&mut self.state);
+    pub fn with_memory_size(input: Vec<u8>, size: usize) -> Self {
+        Self {
+            state: State::with_memory_size(input, size),
+        }
+    }
+
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder::default()
     }
 
-    pub fn print_state(&self, verbose: bool) {
+    pub fn interpret(&mut self, program: &mut dyn Interpret) -> Result<(), RuntimeError> {
+        program.interpret(&mut self.state)
+    }
+
+    /// Run compiled bytecode, counting steps and guarding loops the same
+    /// way as the syntax tree
+    pub fn run_bytecode(&mut self, bytecode: &mut Bytecode) -> Result<(), RuntimeError> {
+        execute(bytecode, &mut self.state)
+    }
+
+    /// Move the output out of the state, leaving it empty
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.state.output)
+    }
+
+    /// Print the output, with the whole state when verbose. `window` limits
+    /// the printed memory to a range of cells, clamped to the tape.
+    pub fn print_state(&self, verbose: bool, window: Option<Range<usize>>) {
         if verbose {
-            println!("Memory         :\t {:?}", self.state.memory);
+            match window {
+                Some(window) => {
+                    let end = window.end.min(self.state.memory.len());
+                    let start = window.start.min(end);
+                    println!(
+                        "Memory {:<8}:\t {:?}",
+                        format!("[{}..{}]", start, end),
+                        &self.state.memory[start..end]
+                    );
+                }
+                None => println!("Memory         :\t {:?}", self.state.memory),
+            }
             println!("Pointer        :\t {:?}", self.state.pointer);
             println!("Input          :\t {:?}", self.state.input);
             println!("Output         :\t {:?}", self.state.output);
-            println!(
-                "Output (UTF-8) :\t {:?}",
-                String::from_utf8(self.state.output.clone()).unwrap()
-            );
+            println!("Output (UTF-8) :\t {:?}", self.state.output_string_lossy());
+        } else {
+            println!("{:?}", self.state.output_string_lossy());
+        }
+    }
+}
+
+/// Fluent configuration for an [`Interpreter`]
+#[derive(Debug, Default)]
+pub struct InterpreterBuilder {
+    input: Vec<u8>,
+    normalize_newlines: bool,
+    numeric_output: bool,
+    echo_input: bool,
+    pointer_mode: PointerMode,
+    memory_size: Option<usize>,
+    max_steps: Option<u64>,
+    max_loop_iterations: Option<u64>,
+    loop_guard: Option<u64>,
+    pointer_history: Option<usize>,
+    growth_limit: Option<usize>,
+    initial_tape: Vec<u8>,
+    memory_logger: Option<Rc<RefCell<dyn MemoryLogger>>>,
+    output_stream: Option<Rc<RefCell<dyn OutputStream>>>,
+}
+
+impl InterpreterBuilder {
+    pub fn input(mut self, input: Vec<u8>) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Normalize input line endings before `,` reads them
+    pub fn normalize_newlines(mut self, normalize: bool) -> Self {
+        self.normalize_newlines = normalize;
+        self
+    }
+
+    /// Make `.` write the cell as a decimal number followed by a space
+    pub fn numeric_output(mut self, numeric: bool) -> Self {
+        self.numeric_output = numeric;
+        self
+    }
+
+    /// Echo bytes consumed by `,` to the output
+    pub fn echo_input(mut self, echo: bool) -> Self {
+        self.echo_input = echo;
+        self
+    }
+
+    /// Choose how the pointer behaves at the ends of the tape
+    pub fn pointer_mode(mut self, mode: PointerMode) -> Self {
+        self.pointer_mode = mode;
+        self
+    }
+
+    /// Number of cells on the tape, defaults to [`MEMORY_SIZE`]
+    pub fn memory_size(mut self, size: usize) -> Self {
+        self.memory_size = Some(size);
+        self
+    }
+
+    /// Abort the program after this many commands
+    pub fn max_steps(mut self, limit: u64) -> Self {
+        self.max_steps = Some(limit);
+        self
+    }
+
+    /// Abort the program when one run of a loop iterates more than `limit`
+    pub fn max_loop_iterations(mut self, limit: u64) -> Self {
+        self.max_loop_iterations = Some(limit);
+        self
+    }
+
+    /// Stop loops that look infinite once `steps` commands ran without I/O
+    pub fn loop_guard(mut self, steps: u64) -> Self {
+        self.loop_guard = Some(steps);
+        self
+    }
+
+    /// Record the last `samples` pointer positions
+    pub fn pointer_history(mut self, samples: usize) -> Self {
+        self.pointer_history = Some(samples);
+        self
+    }
+
+    /// Grow the tape on demand up to `limit` cells
+    pub fn growth_limit(mut self, limit: usize) -> Self {
+        self.growth_limit = Some(limit);
+        self
+    }
+
+    /// Start the tape with these cells instead of zeroes
+    pub fn initial_tape(mut self, cells: Vec<u8>) -> Self {
+        self.initial_tape = cells;
+        self
+    }
+
+    /// Report every cell access to the logger
+    pub fn memory_logger(mut self, logger: Rc<RefCell<dyn MemoryLogger>>) -> Self {
+        self.memory_logger = Some(logger);
+        self
+    }
+
+    /// Stream output to the writer as it is produced, flushing every write.
+    /// It is still collected in the state as well.
+    pub fn output_stream(mut self, stream: Rc<RefCell<dyn OutputStream>>) -> Self {
+        self.output_stream = Some(stream);
+        self
+    }
+
+    pub fn build(self) -> Interpreter {
+        let input = if self.normalize_newlines {
+            normalize_newlines(&self.input)
         } else {
-            println!(
-                "{:?}",
-                String::from_utf8(self.state.output.clone()).unwrap()
-            );
+            self.input
+        };
+        let mut interpreter = match (self.memory_size, self.growth_limit) {
+            (Some(size), _) => Interpreter::with_memory_size(input, size),
+            (None, Some(_)) => Interpreter {
+                state: State::new_growable(input),
+            },
+            (None, None) => Interpreter::new(input),
+        };
+        if let Some(limit) = self.growth_limit {
+            interpreter.state.growth_limit = Some(limit);
         }
+        interpreter.state.max_steps = self.max_steps;
+        interpreter.state.max_loop_iterations = self.max_loop_iterations;
+        interpreter.state.loop_guard = self.loop_guard;
+        interpreter.state.pointer_history = self.pointer_history.map(PointerHistory::new);
+        interpreter.state.initial_tape = self.initial_tape;
+        interpreter.state.load_initial_tape();
+        interpreter.state.numeric_output = self.numeric_output;
+        interpreter.state.echo_input = self.echo_input;
+        interpreter.state.pointer_mode = self.pointer_mode;
+        interpreter.state.memory_logger = self.memory_logger;
+        interpreter.state.output_stream = self.output_stream;
+        interpreter
     }
 }
@@ -8,12 +8,18 @@
 //! - `[]` : Loop
 //! - `.`  : Put char
 //! - `,`  : Read char
+//! - `#`  : Comment until the end of the line, `;` works the same way
+//!
+//! A `#!` line at the very start is a shebang and is skipped, so that
+//! source files can be run as scripts.
 //!
 //! When the code is parsed into tokens, it is called **regular language**
 //! Tokens are also called **lexemes**.
 
 use std::fmt::{Display, Formatter};
 
+/// Line and column of a token, both counted from 0. They are displayed
+/// counted from 1, like editors show them.
 #[derive(Debug, Clone, Copy)]
 pub struct Position(u32, u32);
 
@@ -38,6 +44,16 @@ impl Position {
     pub fn increment_offset(&mut self) {
         self.1 += 1;
     }
+
+    pub fn advance_offset(&mut self, columns: u32) {
+        self.1 += columns;
+    }
+
+    /// Move to the next multiple of `tab_width`, where a tab takes the offset
+    pub fn advance_to_tab_stop(&mut self, tab_width: u32) {
+        let tab_width = tab_width.max(1);
+        self.1 = (self.1 / tab_width + 1) * tab_width;
+    }
 }
 
 impl Display for Position {
@@ -50,7 +66,27 @@ impl Display for Position {
     }
 }
 
-pub type LexError = (Position, String);
+/// A character that is neither a command, whitespace nor a comment
+#[derive(Debug, Clone, Copy)]
+pub struct LexError {
+    pub character: char,
+    pub position: Position,
+}
+
+impl LexError {
+    pub fn new(character: char, position: Position) -> Self {
+        Self {
+            character,
+            position,
+        }
+    }
+}
+
+impl Display for LexError {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        formatter.write_fmt(format_args!("Unrecognized character: {}", self.character))
+    }
+}
 
 #[derive(Debug)]
 pub struct Token {
@@ -73,6 +109,7 @@ impl Token {
 }
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum TokenKind {
     Right(usize),
     Left(usize),
@@ -85,94 +122,284 @@ pub enum TokenKind {
     EOF,
 }
 
+impl TokenKind {
+    /// Canonical source character, `None` for `EOF`
+    pub fn as_char(&self) -> Option<char> {
+        match self {
+            TokenKind::Right(_) => Some('>'),
+            TokenKind::Left(_) => Some('<'),
+            TokenKind::Increment(_) => Some('+'),
+            TokenKind::Decrement(_) => Some('-'),
+            TokenKind::LoopStart => Some('['),
+            TokenKind::LoopEnd => Some(']'),
+            TokenKind::PutChar => Some('.'),
+            TokenKind::ReadChar => Some(','),
+            TokenKind::EOF => None,
+        }
+    }
+
+    /// Number of source characters the token stands for
+    pub fn count(&self) -> usize {
+        match self {
+            TokenKind::Right(count)
+            | TokenKind::Left(count)
+            | TokenKind::Increment(count)
+            | TokenKind::Decrement(count) => *count,
+            TokenKind::EOF => 0,
+            _ => 1,
+        }
+    }
+}
+
 impl Display for TokenKind {
     fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
-        formatter.write_fmt(format_args!(
-            "{:?}",
-            match self {
-                TokenKind::Right(_) => ">",
-                TokenKind::Left(_) => "<",
-                TokenKind::Increment(_) => "+",
-                TokenKind::Decrement(_) => "-",
-                TokenKind::LoopStart => "[",
-                TokenKind::LoopEnd => "]",
-                TokenKind::PutChar => ".",
-                TokenKind::ReadChar => ",",
-                TokenKind::EOF => "EOF",
+        match self.as_char() {
+            Some(symbol) => formatter.write_fmt(format_args!("{}", symbol)),
+            None => formatter.write_str("EOF"),
+        }
+    }
+}
+
+/// Comment text along with the index of the token it precedes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    pub token_index: usize,
+    pub text: String,
+}
+
+impl Comment {
+    pub fn new(token_index: usize, text: String) -> Self {
+        Self { token_index, text }
+    }
+}
+
+/// First line of source files that run themselves through `brainfreeze`,
+/// `-S` lets `env` split the command on Linux
+pub const SHEBANG: &str = "#!/usr/bin/env -S brainfreeze run";
+
+/// Render tokens back into Brainf*ck source, reattaching comments after
+/// `comment_char`
+pub fn render(tokens: &[Token], comments: &[Comment], comment_char: char) -> String {
+    let mut source = String::new();
+    let mut comments = comments.iter().peekable();
+
+    let push_comment = |source: &mut String, comment: &Comment| {
+        if !source.is_empty() && !source.ends_with('\n') {
+            source.push(' ');
+        }
+        source.push(comment_char);
+        source.push_str(&comment.text);
+        source.push('\n');
+    };
+
+    for (index, token) in tokens.iter().enumerate() {
+        while let Some(comment) = comments.next_if(|c| c.token_index <= index) {
+            push_comment(&mut source, comment);
+        }
+
+        if let Some(symbol) = token.kind().as_char() {
+            source.extend(std::iter::repeat_n(symbol, token.kind().count()));
+        }
+    }
+
+    for comment in comments {
+        push_comment(&mut source, comment);
+    }
+
+    source
+}
+
+/// Format tokens as a table of kind, count and position
+pub fn token_table(tokens: &[Token]) -> String {
+    let mut table = String::from("Index  Kind  Count  Position\n");
+
+    for (index, token) in tokens.iter().enumerate() {
+        table.push_str(&format!(
+            "{:<5}  {:<4}  {:<5}  {}\n",
+            index,
+            token.kind().to_string(),
+            token.kind().count(),
+            token.position()
+        ));
+    }
+
+    table
+}
+
+/// Whether every `[` in the source has been closed, ignoring comments
+/// started by `comment_chars`. Stray `]` count as balanced so that the
+/// parser can report them.
+pub fn is_balanced(source: &str, comment_chars: &[char]) -> bool {
+    let (unclosed, _) = bracket_balance(source, comment_chars);
+    unclosed == 0
+}
+
+/// Whether every `[` has been closed and every `]` has been opened
+pub fn is_strictly_balanced(source: &str, comment_chars: &[char]) -> bool {
+    bracket_balance(source, comment_chars) == (0, 0)
+}
+
+/// Number of unclosed `[` and stray `]`, ignoring comments
+fn bracket_balance(source: &str, comment_chars: &[char]) -> (usize, usize) {
+    let mut depth = 0usize;
+    let mut stray = 0;
+
+    for line in source.lines() {
+        let code = line.split(comment_chars).next().unwrap_or_default();
+        for char in code.chars() {
+            match char {
+                '[' => depth += 1,
+                ']' if depth == 0 => stray += 1,
+                ']' => depth -= 1,
+                _ => {}
             }
-            .to_string()
-        ))
+        }
     }
+
+    (depth, stray)
 }
 
+/// Characters that start a comment unless configured otherwise
+pub const DEFAULT_COMMENT_CHARS: &[char] = &['#', ';'];
+
 pub struct Lexer {
     inner: String,
+    fail_fast: bool,
+    comment_chars: Vec<char>,
+    tab_width: u32,
 }
 
 impl Lexer {
     pub fn new(inner: String) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            fail_fast: false,
+            comment_chars: DEFAULT_COMMENT_CHARS.to_vec(),
+            tab_width: 1,
+        }
+    }
+
+    /// Stop scanning at the first error instead of collecting all of them
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Set which characters start a comment
+    pub fn comment_chars(mut self, comment_chars: &[char]) -> Self {
+        self.comment_chars = comment_chars.to_vec();
+        self
+    }
+
+    /// Columns between tab stops, matching the reader's editor. With a
+    /// width of 4, `x` in `\tx` is at offset 4, displayed as 5.
+    pub fn tab_width(mut self, tab_width: u32) -> Self {
+        self.tab_width = tab_width;
+        self
+    }
+
+    /// Whether every `[` has been closed, with this lexer's comment chars
+    pub fn is_balanced(&self) -> bool {
+        is_balanced(&self.inner, &self.comment_chars)
     }
 
     pub fn scan_tokens(self) -> Result<Vec<Token>, Vec<LexError>> {
+        self.scan_tokens_with_comments().map(|(tokens, _)| tokens)
+    }
+
+    pub fn scan_tokens_with_comments(self) -> Result<(Vec<Token>, Vec<Comment>), Vec<LexError>> {
         use TokenKind::*;
 
-        let chars = self.inner.chars();
-        let mut stacked: Option<(char, usize)> = None;
+        // Skip the shebang but keep its newline so line numbers stay right
+        let source = match self.inner.strip_prefix("#!") {
+            Some(rest) => &rest[rest.find('\n').unwrap_or(rest.len())..],
+            None => &self.inner[..],
+        };
+        let chars = source.chars();
+        // Stacked character with its count and the position of its first char
+        let mut stacked: Option<(char, usize, Position)> = None;
         let mut tokens: Vec<Token> = Vec::new();
         let mut errors: Vec<LexError> = Vec::new();
+        let mut comments: Vec<Comment> = Vec::new();
+        let mut comment: Option<String> = None;
 
         let mut position = Position::new(0, 0);
 
         for char in chars {
-            position.increment_offset();
+            // Tokens start at the current position, the next char follows it
+            let current = position;
+            match char {
+                '\n' => position.increment_line_number(),
+                '\t' => position.advance_to_tab_stop(self.tab_width),
+                _ => position.increment_offset(),
+            }
+            let position = current;
+
+            // Everything until the end of the line belongs to the comment
+            if let Some(text) = comment.as_mut() {
+                if char != '\n' {
+                    text.push(char);
+                    continue;
+                }
+                comments.push(Comment::new(tokens.len(), comment.take().unwrap()));
+            }
 
-            if let Some((stack_char, stack_count)) = stacked {
+            if let Some((stack_char, stack_count, start)) = stacked {
                 if char != stack_char {
                     // Push stacked token to tokens
-                    match stack_char {
-                        '+' => tokens.push(Token::new(Increment(stack_count), position)),
-                        '-' => tokens.push(Token::new(Decrement(stack_count), position)),
-                        '>' => tokens.push(Token::new(Right(stack_count), position)),
-                        '<' => tokens.push(Token::new(Left(stack_count), position)),
-                        _ => {}
-                    }
+                    tokens.extend(stacked_token(stack_char, stack_count, start));
                     stacked = None;
                 }
             }
 
             match char {
                 '+' | '-' | '<' | '>' => {
-                    let default = Some((char, 1));
-                    stacked = stacked.map_or(default, |(ch, cnt)| Some((ch, cnt + 1)));
+                    let default = Some((char, 1, position));
+                    stacked =
+                        stacked.map_or(default, |(ch, cnt, start)| Some((ch, cnt + 1, start)));
                 }
                 '.' => tokens.push(Token::new(PutChar, position)),
                 ',' => tokens.push(Token::new(ReadChar, position)),
                 '[' => tokens.push(Token::new(LoopStart, position)),
                 ']' => tokens.push(Token::new(LoopEnd, position)),
-                '\n' => position.increment_line_number(),
-                ' ' | '\t' => {}
-                _ => errors.push((position, format!("Unrecognized character: {}", char))),
+                char if self.comment_chars.contains(&char) => comment = Some(String::new()),
+                ' ' | '\t' | '\n' => {}
+                _ => {
+                    errors.push(LexError::new(char, position));
+                    if self.fail_fast {
+                        return Err(errors);
+                    }
+                }
             }
         }
 
+        if let Some(text) = comment {
+            comments.push(Comment::new(tokens.len(), text));
+        }
+
         // If there's anything stacked left, take it
-        if let Some((stack_char, stack_count)) = stacked {
-            match stack_char {
-                '+' => tokens.push(Token::new(Increment(stack_count), position)),
-                '-' => tokens.push(Token::new(Decrement(stack_count), position)),
-                '>' => tokens.push(Token::new(Right(stack_count), position)),
-                '<' => tokens.push(Token::new(Left(stack_count), position)),
-                _ => {}
-            }
+        if let Some((stack_char, stack_count, start)) = stacked {
+            tokens.extend(stacked_token(stack_char, stack_count, start));
         }
 
+        // `EOF` sits right after the last character
         tokens.push(Token::new(EOF, position));
 
-        if errors.len() == 0 {
-            Ok(tokens)
+        if errors.is_empty() {
+            Ok((tokens, comments))
         } else {
             Err(errors)
         }
     }
 }
+
+fn stacked_token(char: char, count: usize, position: Position) -> Option<Token> {
+    let kind = match char {
+        '+' => TokenKind::Increment(count),
+        '-' => TokenKind::Decrement(count),
+        '>' => TokenKind::Right(count),
+        '<' => TokenKind::Left(count),
+        _ => return None,
+    };
+    Some(Token::new(kind, position))
+}
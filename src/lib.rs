@@ -0,0 +1,78 @@
+//! # Brainfreeze
+//! Brainf*ck interpreter that can hide programs inside PNG images.
+//!
+//! Source goes through [`lexer`], [`parser`] and finally [`interpreter`].
+//! [`img`] reads and writes programs embedded in images.
+
+pub mod binary;
+pub mod bytecode;
+pub mod cache;
+mod cipher;
+pub mod clipboard;
+mod compress;
+pub mod direct;
+pub mod editor;
+pub mod format;
+pub mod header;
+pub mod img;
+pub mod interpreter;
+pub mod lexer;
+pub mod parser;
+pub mod presets;
+pub mod stats;
+
+use std::fmt::Display;
+
+use interpreter::{Interpreter, RuntimeError};
+use lexer::{LexError, Lexer};
+use parser::{ParseError, Parser};
+
+#[derive(Debug)]
+pub enum BrainfreezeError {
+    Lex(Vec<LexError>),
+    Parse(Vec<ParseError>),
+    Runtime(RuntimeError),
+}
+
+impl Display for BrainfreezeError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrainfreezeError::Lex(errors) => {
+                for error in errors {
+                    writeln!(
+                        formatter,
+                        "Syntax error at position {}: {}",
+                        error.position, error
+                    )?;
+                }
+                Ok(())
+            }
+            BrainfreezeError::Parse(errors) => {
+                for (position, message) in errors {
+                    writeln!(formatter, "Error at position {}: {}", position, message)?;
+                }
+                Ok(())
+            }
+            BrainfreezeError::Runtime(error) => {
+                formatter.write_fmt(format_args!("Runtime error: {}", error))
+            }
+        }
+    }
+}
+
+/// Run Brainf*ck source with the given input, returning its output
+pub fn run(source: &str, input: Vec<u8>) -> Result<Vec<u8>, BrainfreezeError> {
+    let tokens = Lexer::new(source.to_string())
+        .scan_tokens()
+        .map_err(BrainfreezeError::Lex)?;
+    let mut program = Parser::new(tokens)
+        .parse()
+        .map_err(BrainfreezeError::Parse)?;
+
+    let mut interpreter = Interpreter::builder().input(input).build();
+    interpreter
+        .interpret(&mut program)
+        .map_err(BrainfreezeError::Runtime)?;
+
+    Ok(interpreter.take_output())
+}
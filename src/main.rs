@@ -1,10 +1,13 @@
-mod img;
-mod interpreter;
-mod lexer;
-mod parser;
-
-use clap::{Parser, Subcommand};
-use std::io::{BufRead, Write};
+use brainfreeze::{
+    binary, bytecode, cache, clipboard, direct, editor, format, header, img, interpreter, lexer,
+    parser, presets, stats,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{
+    cell::RefCell,
+    io::{BufRead, Write},
+    rc::Rc,
+};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -15,151 +18,1780 @@ use std::io::{BufRead, Write};
 struct Args {
     #[command(subcommand)]
     command: Commands,
+
+    #[arg(
+        long,
+        global = true,
+        overrides_with = "collect_errors",
+        help = "Stop at the first syntax error"
+    )]
+    fail_fast: bool,
+
+    #[arg(
+        long,
+        global = true,
+        overrides_with = "fail_fast",
+        help = "Report every syntax error (default)"
+    )]
+    collect_errors: bool,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "#;",
+        help = "Characters that start a comment until the end of the line"
+    )]
+    comment_chars: String,
+
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1,
+        help = "Columns between tab stops in reported positions, which count from 1"
+    )]
+    tab_width: u32,
+}
+
+/// How the program output is printed
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+enum OutputFormat {
+    /// Quoted string, with the whole state when verbose
+    #[default]
+    Text,
+    /// JSON array of the output bytes
+    BytesJson,
+    /// Output bytes as they are, for programs producing binary data
+    Raw,
+}
+
+/// Resets text attributes and shows the cursor again
+const TERMINAL_RESET: &[u8] = b"\x1b[0m\x1b[?25h";
+
+/// Source dialects understood by the lexer
+const DIALECTS: &[(&str, &str)] = &[(
+    "standard",
+    "Brainf*ck, with `#` and `;` comments until the end of the line",
+)];
+
+/// Prints every cell access to stderr for `--log-memory`
+#[derive(Debug)]
+struct MemoryAccessPrinter;
+
+impl interpreter::MemoryLogger for MemoryAccessPrinter {
+    fn log(&mut self, access: interpreter::Access, index: usize, value: u8) {
+        let access = match access {
+            interpreter::Access::Read => "read ",
+            interpreter::Access::Write => "write",
+        };
+        eprintln!("{} cell {:>3} = {}", access, index, value);
+    }
+}
+
+/// Options shared by the commands that interpret a program
+#[derive(clap::Args, Debug)]
+struct RunOptions {
+    #[arg(short, long, help = "Verbose output", default_value = "false")]
+    verbose: bool,
+
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["START", "LEN"],
+        requires = "verbose",
+        help = "Only print this range of cells in verbose output"
+    )]
+    memory_window: Option<Vec<usize>>,
+
+    #[arg(
+        long,
+        value_name = "TEXT",
+        conflicts_with = "repeat_until_eof",
+        help = "Input read by `,`, encoded as UTF-8 so a multibyte character takes several reads"
+    )]
+    input_string: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["input_string", "repeat_until_eof"],
+        help = "Read input for `,` from a file, `-` reads it from stdin"
+    )]
+    input: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["input", "input_string", "repeat_until_eof"],
+        help = "Read input for `,` from stdin, same as `--input -`"
+    )]
+    stdin: bool,
+
+    /// Input bytes once loaded by [`RunOptions::load_input`]
+    #[arg(skip)]
+    input_bytes: Vec<u8>,
+
+    #[arg(long, value_enum, default_value_t, help = "How to print the output")]
+    output_format: OutputFormat,
+
+    #[arg(long, help = "Strip a trailing newline from the output")]
+    compact_output: bool,
+
+    #[arg(long, help = "Translate \\r\\n line endings in input to \\n")]
+    normalize_newlines: bool,
+
+    #[arg(long, help = "Print cells as decimal numbers instead of characters")]
+    numeric_output: bool,
+
+    #[arg(long, help = "Echo bytes read by `,` to the output")]
+    echo_input: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "What to do when the pointer moves past either end of the tape"
+    )]
+    pointer_mode: interpreter::PointerMode,
+
+    #[arg(
+        long,
+        default_value_t = interpreter::MEMORY_SIZE,
+        value_parser = parse_memory_size,
+        help = "Number of cells on the tape"
+    )]
+    memory_size: usize,
+
+    #[arg(long, help = "Stop the program after this many commands")]
+    max_steps: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Stop the program when one run of a loop iterates more than this"
+    )]
+    max_loop_iterations: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Stop loops that cycle without changing their cell long after the last I/O"
+    )]
+    halt_on_zero_output_loop: bool,
+
+    #[arg(
+        long,
+        value_name = "MAX_SAMPLES",
+        num_args = 0..=1,
+        default_missing_value = "1000",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Print where the pointer went, keeping the last MAX_SAMPLES positions"
+    )]
+    pointer_history: Option<u32>,
+
+    #[arg(long, help = "Grow the tape when the pointer moves past its end")]
+    grow_tape: bool,
+
+    #[arg(
+        long,
+        value_name = "CELLS",
+        default_value_t = interpreter::GROWTH_LIMIT,
+        requires = "grow_tape",
+        alias = "max-memory-bytes",
+        help = "Largest size a growing tape may reach, moving past it is a runtime error"
+    )]
+    max_tape_size: usize,
+
+    #[arg(long, help = "Print every memory read and write to stderr")]
+    log_memory: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with = "log_memory",
+        help = "Report the N most written cells after the run"
+    )]
+    profile_hot_cells: Option<usize>,
+
+    #[arg(long, help = "Print the token stream before parsing")]
+    dump_tokens: bool,
+
+    #[arg(
+        long,
+        help = "Run the token stream directly instead of building the syntax tree"
+    )]
+    interpreter_only: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "interpreter_only",
+        help = "Compile the syntax tree to bytecode and run that instead"
+    )]
+    bytecode: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        requires = "bytecode",
+        help = "Loops the bytecode replaces with arithmetic, none runs them as written"
+    )]
+    opt_level: bytecode::OptLevel,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the tape as JSON to this file when a runtime error occurs"
+    )]
+    tape_snapshot_on_error: Option<String>,
+
+    #[arg(long, help = "Print execution statistics as JSON")]
+    stats_json: bool,
+
+    #[arg(
+        long,
+        help = "Run the program again for every line of stdin until EOF, collecting the output"
+    )]
+    repeat_until_eof: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["output_format", "compact_output"],
+        help = "Write output to stdout as the program produces it"
+    )]
+    stream: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["output_format", "compact_output", "stream"],
+        help = "Stream output for terminal animations, resetting colors and the cursor afterwards"
+    )]
+    raw_terminal: bool,
+}
+
+impl RunOptions {
+    /// Cells picked by --memory-window
+    fn memory_window(&self) -> Option<std::ops::Range<usize>> {
+        match self.memory_window.as_deref() {
+            Some(&[start, length]) => Some(start..start.saturating_add(length)),
+            _ => None,
+        }
+    }
+
+    /// Read the program input once, so that stdin can be used for it and
+    /// every run sees the same bytes
+    fn load_input(&mut self) {
+        if self.stdin {
+            self.input = Some("-".to_string());
+        }
+        self.input_bytes = match (&self.input, &self.input_string) {
+            (Some(path), _) if path == "-" => {
+                let mut bytes = Vec::new();
+                if let Err(e) = std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes) {
+                    eprintln!("Error while reading stdin: {}", e);
+                    std::process::exit(1);
+                }
+                bytes
+            }
+            (Some(path), _) => read_bytes_or_exit(path),
+            (None, Some(text)) => text.as_bytes().to_vec(),
+            (None, None) => Vec::new(),
+        };
+    }
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     #[command(about = "Inject a Brainf*ck program into a PNG image")]
     Inject {
-        #[arg(help = "PNG image to inject into")]
-        image: String,
+        #[arg(
+            help = "PNG image to inject into, left out with --binary",
+            required_unless_present = "binary"
+        )]
+        image: Option<String>,
 
         #[arg(help = "Brainf*ck program")]
-        program: String,
+        program: Option<String>,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = ["animated", "offset"],
+            help = "Write the encoded program to a .bfc file instead of an image"
+        )]
+        binary: Option<String>,
 
         #[arg(short, long, help = "Output file", default_value = "out.png")]
         output: String,
+
+        #[arg(
+            long = "meta",
+            value_name = "KEY=VALUE",
+            value_parser = parse_meta,
+            help = "Metadata to store alongside the program"
+        )]
+        metadata: Vec<(String, String)>,
+
+        #[arg(long, help = "Store comments so they can be recovered with extract")]
+        keep_comments: bool,
+
+        #[arg(long, help = "Spread the program across the frames of an animated PNG")]
+        animated: bool,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "delta",
+            help = "How tokens are laid out in the pixels"
+        )]
+        encoding: header::Encoding,
+
+        #[arg(long, help = "Print the token stream before writing")]
+        dump_tokens: bool,
+
+        #[arg(
+            long,
+            conflicts_with = "animated",
+            help = "Store the program as compressed source to fit larger programs"
+        )]
+        compress: bool,
+
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Number of pixels to skip before the program, e.g. a border"
+        )]
+        offset: usize,
+
+        #[arg(
+            long,
+            conflicts_with_all = ["animated", "binary"],
+            help = "Encrypt the program so it can only be read with this password"
+        )]
+        password: Option<String>,
     },
     #[command(about="Execute a Brainf*ck program from a PNG image", aliases=["exec"])]
     Execute {
-        #[arg(help = "PNG image to execute")]
+        #[arg(help = "PNG image or .bfc program to execute, - reads the PNG from stdin")]
         image: String,
 
-        #[arg(short, long, help = "Verbose output", default_value = "false")]
-        verbose: bool,
+        #[command(flatten)]
+        options: Box<RunOptions>,
+
+        #[arg(
+            long,
+            help = "Exit with an error if the output differs from this string"
+        )]
+        assert_output: Option<String>,
+
+        #[arg(long, help = "Print metadata stored in the image")]
+        show_meta: bool,
+
+        #[arg(long, help = "Report how many times each loop ran")]
+        count_loops: bool,
+
+        #[arg(
+            long,
+            help = "Print the tape from the pointer to the rightmost cell reached"
+        )]
+        since_pointer: bool,
+
+        #[arg(long, help = "Validate and report the program without running it")]
+        dry_run: bool,
+
+        #[arg(
+            long,
+            conflicts_with = "dry_run",
+            help = "Print the decoded source and stop before parsing it"
+        )]
+        decode_only: bool,
+
+        #[arg(long, help = "Refuse to run a program with unbalanced brackets")]
+        strict_brackets: bool,
+
+        #[arg(
+            long,
+            value_name = "START:END",
+            value_parser = parse_rows,
+            help = "Only decode this range of rows, END is exclusive"
+        )]
+        rows: Option<std::ops::Range<u32>>,
+
+        #[arg(
+            long,
+            value_name = "OUTPUT",
+            help = "Inject the program output, as Brainf*ck source, into a copy of the image"
+        )]
+        output_to_image: Option<String>,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Save the output bytes as an image, a pixel per byte"
+        )]
+        output_image: Option<String>,
+
+        #[arg(
+            long,
+            requires = "output_image",
+            default_value_t = 64,
+            value_parser = clap::value_parser!(u32).range(1..),
+            help = "Width of the --output-image in pixels"
+        )]
+        width: u32,
+
+        #[arg(
+            long,
+            requires = "output_image",
+            help = "Make every three output bytes an RGB pixel instead of gray"
+        )]
+        rgb: bool,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Run the program once per byte of the file, collecting the output"
+        )]
+        map_input: Option<String>,
+
+        #[arg(long, help = "Start the tape with bytes sampled from the image pixels")]
+        seed_from_image: bool,
+
+        #[arg(
+            long,
+            value_name = "OFFSET",
+            num_args = 1..,
+            conflicts_with_all = ["map_input", "repeat_until_eof", "dry_run", "decode_only"],
+            help = "Run the programs at these pixel offsets after the first, each reading the previous output as input"
+        )]
+        pipeline: Vec<usize>,
+
+        #[arg(
+            long,
+            value_name = "IMAGE",
+            conflicts_with_all = ["map_input", "repeat_until_eof", "stream", "raw_terminal"],
+            help = "Run another image too and report where their behavior diverges"
+        )]
+        compare_image: Option<String>,
+
+        #[arg(
+            long,
+            requires = "compare_image",
+            help = "Also compare the final tapes of the compared images"
+        )]
+        compare_tape: bool,
+
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Number of pixels to skip before the program, e.g. a border"
+        )]
+        offset: usize,
+
+        #[arg(long, help = "Password the program was encrypted with")]
+        password: Option<String>,
+
+        #[arg(
+            long,
+            help = "Read images written before the format header, from the offset"
+        )]
+        headerless: bool,
+    },
+    #[command(about = "Run a Brainf*ck program from a source file")]
+    Run {
+        #[arg(help = "Brainf*ck program", required_unless_present = "clipboard")]
+        program: Option<String>,
+
+        #[arg(
+            long,
+            help = "Read the program from the system clipboard",
+            conflicts_with = "program"
+        )]
+        clipboard: bool,
+
+        #[command(flatten)]
+        options: RunOptions,
+    },
+    #[command(about = "Parse a Brainf*ck program and print its syntax tree")]
+    Parse {
+        #[arg(help = "Brainf*ck program")]
+        program: String,
+
+        #[arg(long, help = "Print the tree as a Graphviz DOT graph")]
+        dot: bool,
     },
-    #[command(about = "Run a REPL (Read, Evaluate, Print, Loop) environment")]
+    #[command(about = "Extract the Brainf*ck source from a PNG image")]
+    Extract {
+        #[arg(help = "PNG image to extract from, - reads it from stdin")]
+        image: String,
+
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Number of pixels to skip before the program, e.g. a border"
+        )]
+        offset: usize,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Write the source as a script that runs itself through brainfreeze"
+        )]
+        executable: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            conflicts_with = "executable",
+            help = "Write the source to a file instead of stdout"
+        )]
+        output: Option<String>,
+
+        #[arg(long, help = "Password the program was encrypted with")]
+        password: Option<String>,
+
+        #[arg(
+            long,
+            help = "Read images written before the format header, from the offset"
+        )]
+        headerless: bool,
+    },
+    #[command(about = "Report how large a program a PNG image can hold")]
+    Capacity {
+        #[arg(help = "PNG image to inject into")]
+        image: String,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "delta",
+            help = "How tokens are laid out in the pixels"
+        )]
+        encoding: header::Encoding,
+
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Number of pixels to skip before the program, e.g. a border"
+        )]
+        offset: usize,
+    },
+    #[command(about = "Rewrite Brainf*ck source in a canonical layout")]
+    Fmt {
+        #[arg(help = "Brainf*ck program")]
+        program: String,
+
+        #[arg(short, long, help = "Write the result back to the file")]
+        write: bool,
+
+        #[arg(long, help = "Drop comments from the result")]
+        strip_comments: bool,
+    },
+    #[command(about = "Run built-in benchmark programs")]
+    Bench {
+        #[arg(short, long, help = "Preset to run, runs every preset when omitted")]
+        program: Option<String>,
+
+        #[arg(long, help = "Compile the presets to bytecode before running them")]
+        bytecode: bool,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value_t,
+            requires = "bytecode",
+            help = "Loops the bytecode replaces with arithmetic"
+        )]
+        opt_level: bytecode::OptLevel,
+    },
+    #[command(about = "List supported dialects and encodings")]
+    List {
+        #[arg(long, help = "List source dialects")]
+        dialects: bool,
+
+        #[arg(long, help = "List token encodings for --encoding")]
+        encodings: bool,
+    },
+    #[command(
+        about = "Run a REPL (Read, Evaluate, Print, Loop) environment",
+        long_about = "Run a REPL (Read, Evaluate, Print, Loop) environment. The tape and pointer carry over between lines.\n\nMeta-commands:\n  :mem [n]      print the first n cells\n  :ptr          print the pointer\n  :reset        zero the tape and pointer\n  :input TEXT   queue input for , reads\n  :quit         exit the REPL"
+    )]
     Repl {
         #[arg(short, long, help = "Verbose output", default_value = "false")]
         verbose: bool,
+
+        #[arg(long, help = "Prompt printed before each line", default_value = "> ")]
+        prompt: String,
+
+        #[arg(
+            long,
+            help = "Prompt printed while a loop is left open",
+            default_value = "... "
+        )]
+        continuation_prompt: String,
+
+        #[arg(
+            long,
+            default_value_t = interpreter::MEMORY_SIZE,
+            value_parser = parse_memory_size,
+            help = "Number of cells on the tape"
+        )]
+        memory_size: usize,
+
+        #[arg(long, help = "Write output as it is produced, bypassing the cache")]
+        stream: bool,
+
+        #[arg(long, help = "Stop each line after this many commands")]
+        max_steps: Option<u64>,
     },
 }
 
 fn main() {
     use interpreter::Interpreter;
     use lexer::Lexer;
-    use parser::Parser;
 
     let args = Args::parse();
+    let fail_fast = args.fail_fast;
+    let comment_chars: Vec<char> = args.comment_chars.chars().collect();
+    // Comments are written back with the first comment char of the dialect
+    let comment_char = comment_chars
+        .first()
+        .copied()
+        .unwrap_or(lexer::DEFAULT_COMMENT_CHARS[0]);
+    let tab_width = args.tab_width;
 
     match args.command {
         Commands::Inject {
             image,
             program,
+            binary,
             output,
+            metadata,
+            keep_comments,
+            animated,
+            encoding,
+            dump_tokens,
+            compress,
+            offset,
+            password,
         } => {
-            let file_contents = match std::fs::read_to_string(program) {
-                Ok(contents) => contents,
-                Err(e) => {
-                    eprintln!("Error while reading file: {}", e);
+            // Without an image the only positional argument is the program
+            let (image, program) = match (image, program, &binary) {
+                (Some(program), None, Some(_)) => (None, program),
+                (image, Some(program), _) => (image, program),
+                _ => {
+                    eprintln!("Missing the program to inject");
                     std::process::exit(1);
                 }
             };
+            let file_contents = read_source_or_exit(&program);
+            // Whitespace carries no meaning, only dropping anything else is
+            // worth a note
+            let source_length = file_contents.chars().filter(|c| !c.is_whitespace()).count();
 
-            let lexer = Lexer::new(file_contents);
-            let tokens = match lexer.scan_tokens() {
-                Ok(tokens) => tokens,
+            let lexer = Lexer::new(file_contents)
+                .fail_fast(fail_fast)
+                .comment_chars(&comment_chars)
+                .tab_width(tab_width);
+            let (tokens, comments) = match lexer.scan_tokens_with_comments() {
+                Ok(scanned) => scanned,
                 Err(errors) => {
-                    for (pos, msg) in errors {
-                        eprintln!("Syntax error at position {pos}: {msg}");
+                    for error in errors {
+                        eprintln!("Syntax error at position {}: {}", error.position, error);
                     }
                     std::process::exit(1);
                 }
             };
 
-            match img::write(&image, &output, tokens) {
-                Ok(_) => {}
+            if dump_tokens {
+                print!("{}", lexer::token_table(&tokens));
+            }
+
+            // Only commands are stored, let users know when that drops comments
+            let stored_length = lexer::render(&tokens, &[], comment_char).chars().count();
+            if !keep_comments && stored_length < source_length {
+                eprintln!(
+                    "Note: {} characters of comments are dropped, keep them with --keep-comments",
+                    source_length - stored_length
+                );
+            }
+
+            let header = header::Header {
+                metadata,
+                comments: if keep_comments { comments } else { vec![] },
+                encoding,
+                compressed: compress,
+                encryption: None,
+                token_count: None,
+            };
+            if let Some(path) = binary {
+                if image.is_some() {
+                    eprintln!("A .bfc file has no image, leave the image out with --binary");
+                    std::process::exit(1);
+                }
+                if let Err(e) = binary::write(&path, &tokens, &header) {
+                    eprintln!("Error while writing file: {}", e);
+                    std::process::exit(1);
+                }
+                println!("Wrote program to {}", path);
+                return;
+            }
+
+            let image = image.expect("image path");
+            let written = if animated {
+                img::write_animated(&image, &output, tokens, &header, offset)
+            } else {
+                img::write(
+                    &image,
+                    &output,
+                    tokens,
+                    &header,
+                    offset,
+                    password.as_deref(),
+                )
+                .map(|_| 1)
+            };
+
+            match written {
+                Ok(1) => println!("Wrote image to {}", output),
+                Ok(frames) => println!("Wrote {} frames to {}", frames, output),
                 Err(e) => {
                     eprintln!("Error while writing image: {}", e);
                     std::process::exit(1);
                 }
             }
+        }
+        Commands::Execute {
+            image,
+            mut options,
+            assert_output,
+            show_meta,
+            count_loops,
+            since_pointer,
+            dry_run,
+            decode_only,
+            strict_brackets,
+            rows,
+            output_to_image,
+            output_image,
+            width,
+            rgb,
+            offset,
+            password,
+            headerless,
+            map_input,
+            seed_from_image,
+            pipeline,
+            compare_image,
+            compare_tape,
+        } => {
+            if image == "-" {
+                if options.stdin || options.input.as_deref() == Some("-") {
+                    eprintln!("stdin can't carry both the image and the program input");
+                    std::process::exit(1);
+                }
+                if seed_from_image || output_to_image.is_some() {
+                    eprintln!("--seed-from-image and --output-to-image need an image file");
+                    std::process::exit(1);
+                }
+            }
+            let (header, tokens) = read_image_or_exit(
+                &image,
+                offset,
+                rows.clone(),
+                password.as_deref(),
+                headerless,
+            );
+
+            if show_meta {
+                let metadata = header
+                    .as_ref()
+                    .map(|header| header.metadata.as_slice())
+                    .unwrap_or_default();
+                if metadata.is_empty() {
+                    println!("No metadata found");
+                }
+                for (key, value) in metadata {
+                    println!("{}: {}", key, value);
+                }
+            }
+
+            let token_count = tokens
+                .iter()
+                .filter(|token| !matches!(token.kind(), lexer::TokenKind::EOF))
+                .count();
+            if options.dump_tokens {
+                print!("{}", lexer::token_table(&tokens));
+            }
+
+            // Stop at the image layer, whatever the tokens make up
+            if decode_only {
+                let comments = header.map(|header| header.comments).unwrap_or_default();
+                println!("Decoded {} tokens", token_count);
+                println!(
+                    "{}",
+                    lexer::render(&tokens, &comments, comment_char).trim_end_matches('\n')
+                );
+                return;
+            }
+
+            if strict_brackets
+                && !lexer::is_strictly_balanced(
+                    &lexer::render(&tokens, &[], comment_char),
+                    &comment_chars,
+                )
+            {
+                eprintln!("Program brackets are unbalanced, refusing to run it");
+                std::process::exit(1);
+            }
+
+            if dry_run {
+                let ast = parse_or_exit(tokens, fail_fast);
+                if ast.is_empty() {
+                    println!("Program is valid but empty");
+                    return;
+                }
+                println!(
+                    "Program is valid: {} tokens, {} characters",
+                    token_count,
+                    ast.source().len()
+                );
+                println!(
+                    "{} top level commands, {} operators in total",
+                    ast.len(),
+                    ast.total_operators()
+                );
+                return;
+            }
+
+            options.load_input();
+            let map_input = map_input.map(|path| read_bytes_or_exit(&path));
+            let seed = seed_from_image.then(|| {
+                img::seed(&image, options.memory_size).unwrap_or_else(|e| {
+                    eprintln!("Error while reading image: {}", e);
+                    std::process::exit(1);
+                })
+            });
+            // Only the last stage of a pipeline prints, the others feed it
+            let mut tokens = tokens;
+            for &stage in &pipeline {
+                let (mut interpreter, result) =
+                    run_quietly(tokens, &options, seed.as_deref(), fail_fast);
+                if let Err(e) = result {
+                    eprintln!("Runtime error in pipeline stage: {}", e);
+                    std::process::exit(1);
+                }
+                options.input_bytes = interpreter.take_output();
+                let (_, stage_tokens) = read_image_or_exit(
+                    &image,
+                    stage,
+                    rows.clone(),
+                    password.as_deref(),
+                    headerless,
+                );
+                tokens = stage_tokens;
+            }
+
+            let (interpreter, loops) = run_tokens(
+                tokens,
+                &options,
+                map_input.as_deref(),
+                seed.as_deref(),
+                fail_fast,
+            );
+
+            if count_loops {
+                for (position, iterations) in loops {
+                    println!("Loop at {}: {} iterations", position, iterations);
+                }
+            }
+
+            if since_pointer {
+                let state = &interpreter.state;
+                println!(
+                    "Tape from cell {}: {:?}",
+                    state.pointer(),
+                    state.tape_since_pointer()
+                );
+            }
+
+            if let Some(output) = output_to_image {
+                let source = interpreter.state.output_string_lossy();
+                let tokens = scan_or_exit(source, fail_fast, &comment_chars, tab_width);
+                if let Err(e) = img::write(
+                    &image,
+                    &output,
+                    tokens,
+                    &header::Header::default(),
+                    offset,
+                    None,
+                ) {
+                    eprintln!("Error while writing image: {}", e);
+                    std::process::exit(1);
+                }
+                println!("Wrote output program to {}", output);
+            }
+
+            if let Some(path) = output_image {
+                match img::render_bytes(&path, &interpreter.state.output, width, rgb) {
+                    Ok(height) => println!("Wrote {}x{} output image to {}", width, height, path),
+                    Err(e) => {
+                        eprintln!("Error while writing image: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            if let Some(expected) = assert_output {
+                let actual = interpreter.state.output_string_lossy();
+                if let Err(diff) = compare_output(&expected, &actual) {
+                    eprintln!("Output assertion failed:\n{}", diff);
+                    std::process::exit(1);
+                }
+            }
+
+            if let Some(other) = compare_image {
+                let (_, tokens) =
+                    read_image_or_exit(&other, offset, rows, password.as_deref(), headerless);
+                let (mut other_interpreter, result) =
+                    run_quietly(tokens, &options, seed.as_deref(), fail_fast);
+                if let Err(e) = result {
+                    eprintln!("Runtime error in {}: {}", other, e);
+                    std::process::exit(1);
+                }
+                if options.compact_output {
+                    other_interpreter.state.strip_trailing_newline();
+                }
+
+                let (state, other_state) = (&interpreter.state, &other_interpreter.state);
+                let expected = state.output_string_lossy();
+                let actual = other_state.output_string_lossy();
+                if let Err(diff) = compare_output(&expected, &actual) {
+                    eprintln!("Output of {} diverges:\n{}", other, diff);
+                    std::process::exit(1);
+                }
+                if compare_tape && state.memory != other_state.memory {
+                    let cell = state
+                        .memory
+                        .iter()
+                        .zip(&other_state.memory)
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    eprintln!(
+                        "Tape of {} diverges at cell {}: {:?} != {:?}",
+                        other,
+                        cell,
+                        state.memory.get(cell),
+                        other_state.memory.get(cell)
+                    );
+                    std::process::exit(1);
+                }
+                println!("{} runs identically", other);
+            }
+        }
+        Commands::Run {
+            program,
+            clipboard,
+            mut options,
+        } => {
+            options.load_input();
+            let source = if clipboard {
+                match clipboard::system().and_then(|mut c| clipboard::read_program(c.as_mut())) {
+                    Ok(source) => source,
+                    Err(e) => {
+                        eprintln!("Error while reading clipboard: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                read_source_or_exit(&program.expect("program path"))
+            };
+
+            let tokens = scan_or_exit(source, fail_fast, &comment_chars, tab_width);
+            if options.dump_tokens {
+                print!("{}", lexer::token_table(&tokens));
+            }
+
+            run_tokens(tokens, &options, None, None, fail_fast);
+        }
+        Commands::Parse { program, dot } => {
+            let file_contents = read_source_or_exit(&program);
+
+            let tokens = scan_or_exit(file_contents, fail_fast, &comment_chars, tab_width);
+
+            let ast = parse_or_exit(tokens, fail_fast);
+
+            if dot {
+                print!("{}", ast.dot());
+            } else {
+                println!("{:#?}", ast);
+            }
+        }
+        Commands::Extract {
+            image,
+            offset,
+            executable,
+            output,
+            password,
+            headerless,
+        } => {
+            let (header, tokens) =
+                read_image_or_exit(&image, offset, None, password.as_deref(), headerless);
 
-            println!("Wrote image to {}", output);
+            let comments = header.map(|header| header.comments).unwrap_or_default();
+            let source = lexer::render(&tokens, &comments, comment_char);
+            let source = source.trim_end_matches('\n');
+
+            match executable {
+                Some(path) => {
+                    let script = format!("{}\n{}\n", lexer::SHEBANG, source);
+                    if let Err(e) = write_executable(&path, &script) {
+                        eprintln!("Error while writing file: {}", e);
+                        std::process::exit(1);
+                    }
+                    println!("Wrote script to {}", path);
+                }
+                None => match output {
+                    Some(path) => {
+                        if let Err(e) = std::fs::write(&path, format!("{}\n", source)) {
+                            eprintln!("Error while writing file: {}", e);
+                            std::process::exit(1);
+                        }
+                        println!("Wrote source to {}", path);
+                    }
+                    None => println!("{}", source),
+                },
+            }
         }
-        Commands::Execute { image, verbose } => {
-            let tokens = match img::read(&image) {
-                Ok(tokens) => tokens,
+        Commands::Capacity {
+            image,
+            encoding,
+            offset,
+        } => {
+            let header = header::Header {
+                encoding,
+                ..Default::default()
+            };
+            let capacity = match img::capacity(&image, &header, offset) {
+                Ok(capacity) => capacity,
                 Err(e) => {
                     eprintln!("Error while reading image: {}", e);
                     std::process::exit(1);
                 }
             };
 
-            if tokens.len() == 0 {
-                println!("No program found");
-                std::process::exit(1);
-            }
+            println!("Pixels available: {}", capacity.pixels);
+            println!("Maximum tokens: {}", capacity.tokens);
+            println!("Longest safe program: {} commands", capacity.commands);
+        }
+        Commands::Fmt {
+            program,
+            write,
+            strip_comments,
+        } => {
+            let file_contents = read_source_or_exit(&program);
+            // The lexer skips the shebang, put it back in front
+            let shebang = file_contents
+                .lines()
+                .next()
+                .filter(|line| line.starts_with("#!"))
+                .map(str::to_string);
 
-            let parser = Parser::new(tokens);
-            let mut ast: parser::Program = match parser.parse() {
-                Ok(ast) => ast,
+            let lexer = Lexer::new(file_contents)
+                .fail_fast(fail_fast)
+                .comment_chars(&comment_chars)
+                .tab_width(tab_width);
+            let (tokens, comments) = match lexer.scan_tokens_with_comments() {
+                Ok(scanned) => scanned,
                 Err(errors) => {
-                    for (pos, msg) in errors {
-                        eprintln!("Error at position {pos}: {msg}");
+                    for error in errors {
+                        eprintln!("Syntax error at position {}: {}", error.position, error);
                     }
                     std::process::exit(1);
                 }
             };
+            if !lexer::is_strictly_balanced(
+                &lexer::render(&tokens, &[], comment_char),
+                &comment_chars,
+            ) {
+                eprintln!("Program brackets are unbalanced, refusing to format it");
+                std::process::exit(1);
+            }
+
+            let comments = if strip_comments { vec![] } else { comments };
+            let mut formatted = format::format(&tokens, &comments, comment_char);
+            if let Some(shebang) = shebang {
+                formatted.insert_str(0, &format!("{}\n", shebang));
+            }
 
-            let mut interpreter = Interpreter::new(vec![]);
-            interpreter.interpret(&mut ast);
-            interpreter.print_state(verbose);
+            if write {
+                if let Err(e) = std::fs::write(&program, formatted) {
+                    eprintln!("Error while writing file: {}", e);
+                    std::process::exit(1);
+                }
+            } else {
+                print!("{}", formatted);
+            }
         }
-        Commands::Repl { verbose } => {
-            run_repl(verbose);
+        Commands::Bench {
+            program,
+            bytecode,
+            opt_level,
+        } => {
+            let selected: Vec<&presets::Preset> = match program {
+                Some(name) => match presets::find(&name) {
+                    Some(preset) => vec![preset],
+                    None => {
+                        let names: Vec<&str> = presets::PRESETS.iter().map(|p| p.name).collect();
+                        eprintln!(
+                            "Unknown preset {}, available presets: {}",
+                            name,
+                            names.join(", ")
+                        );
+                        std::process::exit(1);
+                    }
+                },
+                None => presets::PRESETS.iter().collect(),
+            };
+
+            for preset in selected {
+                let tokens = scan_or_exit(
+                    preset.source.to_string(),
+                    fail_fast,
+                    &comment_chars,
+                    tab_width,
+                );
+                let mut ast = parse_or_exit(tokens, fail_fast);
+
+                let mut builder = Interpreter::builder().input(vec![]);
+                if let Some(limit) = preset.max_steps {
+                    builder = builder.max_steps(limit);
+                }
+                let mut interpreter = builder.build();
+                let started = std::time::Instant::now();
+                let result = if bytecode {
+                    interpreter.run_bytecode(&mut bytecode::Bytecode::compile(&ast, opt_level))
+                } else {
+                    interpreter.interpret(&mut ast)
+                };
+                if let Err(e) = result {
+                    eprintln!("Runtime error in preset {}: {}", preset.name, e);
+                    std::process::exit(1);
+                }
+                let elapsed = started.elapsed();
+
+                println!(
+                    "{:<10} {:>12.3?}  {} ({} bytes of output)",
+                    preset.name,
+                    elapsed,
+                    preset.description,
+                    interpreter.state.output.len()
+                );
+            }
         }
+        Commands::List {
+            dialects,
+            encodings,
+        } => {
+            // List everything when nothing in particular was asked for
+            let all = !dialects && !encodings;
+
+            if dialects || all {
+                println!("Dialects:");
+                for (name, description) in DIALECTS {
+                    println!("  {:<10} {}", name, description);
+                }
+            }
+
+            if encodings || all {
+                println!("Encodings:");
+                for encoding in header::Encoding::value_variants() {
+                    if let Some(value) = encoding.to_possible_value() {
+                        let help = value.get_help().map(ToString::to_string);
+                        println!("  {:<10} {}", value.get_name(), help.unwrap_or_default());
+                    }
+                }
+            }
+        }
+        Commands::Repl {
+            verbose,
+            prompt,
+            continuation_prompt,
+            memory_size,
+            stream,
+            max_steps,
+        } => {
+            run_repl(
+                verbose,
+                &prompt,
+                &continuation_prompt,
+                memory_size,
+                stream,
+                max_steps,
+                |source| {
+                    Lexer::new(source)
+                        .fail_fast(fail_fast)
+                        .comment_chars(&comment_chars)
+                        .tab_width(tab_width)
+                },
+            );
+        }
+    }
+}
+
+/// Parse and run the tokens, or run them directly with `--interpreter-only`.
+/// With `--bytecode` the syntax tree is compiled before running.
+/// Returns the interpreter along with the iteration count of every loop.
+fn run_tokens(
+    tokens: Vec<lexer::Token>,
+    options: &RunOptions,
+    map_input: Option<&[u8]>,
+    seed: Option<&[u8]>,
+    fail_fast: bool,
+) -> (interpreter::Interpreter, Vec<(lexer::Position, usize)>) {
+    use stats::Profile;
+
+    if options.interpreter_only {
+        let mut program = direct_or_exit(&tokens);
+        let interpreter = run_program(&mut program, options, map_input, seed);
+        (interpreter, program.loop_counts())
+    } else if options.bytecode {
+        let ast = parse_or_exit(tokens, fail_fast);
+        let mut bytecode = bytecode::Bytecode::compile(&ast, options.opt_level);
+        let interpreter = run_program(&mut bytecode, options, map_input, seed);
+        (interpreter, bytecode.loop_counts())
+    } else {
+        let mut ast = parse_or_exit(tokens, fail_fast);
+        let interpreter = run_program(&mut ast, options, map_input, seed);
+        (interpreter, ast.loop_counts())
+    }
+}
+
+/// Run tokens on the engine picked by the options like [`run_tokens`], but
+/// without printing anything. Returns the interpreter with the result.
+fn run_quietly(
+    tokens: Vec<lexer::Token>,
+    options: &RunOptions,
+    seed: Option<&[u8]>,
+    fail_fast: bool,
+) -> (
+    interpreter::Interpreter,
+    Result<(), interpreter::RuntimeError>,
+) {
+    let mut interpreter = build_interpreter(options, seed);
+    interpreter.state.output_stream = None;
+
+    let result = if options.interpreter_only {
+        interpreter.interpret(&mut direct_or_exit(&tokens))
+    } else if options.bytecode {
+        let ast = parse_or_exit(tokens, fail_fast);
+        interpreter.run_bytecode(&mut bytecode::Bytecode::compile(&ast, options.opt_level))
+    } else {
+        interpreter.interpret(&mut parse_or_exit(tokens, fail_fast))
+    };
+    (interpreter, result)
+}
+
+/// Interpreter configured by the shared options, with the tape seeded
+fn build_interpreter(options: &RunOptions, seed: Option<&[u8]>) -> interpreter::Interpreter {
+    let mut builder = interpreter::Interpreter::builder()
+        .input(options.input_bytes.clone())
+        .normalize_newlines(options.normalize_newlines)
+        .numeric_output(options.numeric_output)
+        .echo_input(options.echo_input)
+        .pointer_mode(options.pointer_mode)
+        .memory_size(options.memory_size);
+    if let Some(limit) = options.max_steps {
+        builder = builder.max_steps(limit);
+    }
+    if let Some(limit) = options.max_loop_iterations {
+        builder = builder.max_loop_iterations(limit);
+    }
+    if let Some(samples) = options.pointer_history {
+        builder = builder.pointer_history(samples as usize);
+    }
+    if options.halt_on_zero_output_loop {
+        builder = builder.loop_guard(interpreter::LOOP_GUARD_STEPS);
+    }
+    if options.grow_tape {
+        builder = builder.growth_limit(options.max_tape_size);
+    }
+    if let Some(seed) = seed {
+        builder = builder.initial_tape(seed.to_vec());
+    }
+    if options.log_memory {
+        builder = builder.memory_logger(Rc::new(RefCell::new(MemoryAccessPrinter)));
+    }
+    if options.stream || options.raw_terminal {
+        builder = builder.output_stream(Rc::new(RefCell::new(std::io::stdout())));
+    }
+    builder.build()
+}
+
+/// Interpret the program with the shared options and print the results.
+/// With `map_input`, the program runs once per byte with a fresh tape,
+/// `--repeat-until-eof` does the same for every line of stdin. A `seed`
+/// replaces the zeroed tape every run starts with.
+fn run_program<P: interpreter::Interpret + stats::Profile>(
+    program: &mut P,
+    options: &RunOptions,
+    map_input: Option<&[u8]>,
+    seed: Option<&[u8]>,
+) -> interpreter::Interpreter {
+    let mut interpreter = build_interpreter(options, seed);
+    let hot_cells = options.profile_hot_cells.map(|_| {
+        let hot_cells = Rc::new(RefCell::new(stats::HotCells::default()));
+        interpreter.state.memory_logger = Some(hot_cells.clone());
+        hot_cells
+    });
+
+    let started = std::time::Instant::now();
+    let result = match map_input {
+        Some(bytes) => {
+            let chunks = bytes.iter().map(|&byte| vec![byte]);
+            run_chunks(&mut interpreter, program, chunks)
+        }
+        None if options.repeat_until_eof => {
+            let chunks = stdin_lines().map(|line| {
+                if options.normalize_newlines {
+                    interpreter::normalize_newlines(&line)
+                } else {
+                    line
+                }
+            });
+            run_chunks(&mut interpreter, program, chunks)
+        }
+        None => interpreter.interpret(program),
+    };
+    let elapsed = started.elapsed();
+
+    // Don't leave the terminal colored or without a cursor when the
+    // program stops halfway through an escape sequence driven animation
+    if options.raw_terminal {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout
+            .write_all(TERMINAL_RESET)
+            .and_then(|_| stdout.flush());
     }
+
+    if options.compact_output {
+        interpreter.state.strip_trailing_newline();
+    }
+    // Streamed output is already printed
+    match options.output_format {
+        _ if options.stream || options.raw_terminal => {
+            if options.verbose {
+                interpreter.print_state(true, options.memory_window());
+            }
+        }
+        OutputFormat::Text => interpreter.print_state(options.verbose, options.memory_window()),
+        OutputFormat::BytesJson => println!("{}", interpreter.state.output_json()),
+        OutputFormat::Raw => {
+            let mut stdout = std::io::stdout().lock();
+            if stdout
+                .write_all(&interpreter.state.output)
+                .and_then(|_| stdout.flush())
+                .is_err()
+            {
+                eprintln!("Error while writing output");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let exit_reason = match result {
+        Ok(()) => stats::ExitReason::Completed,
+        Err(
+            interpreter::RuntimeError::StepLimitExceeded(_)
+            | interpreter::RuntimeError::LoopIterationLimitExceeded(_),
+        ) => stats::ExitReason::StepLimit,
+        Err(_) => stats::ExitReason::RuntimeError,
+    };
+    if let Some(history) = &interpreter.state.pointer_history {
+        let positions: Vec<String> = history.positions().map(|p| p.to_string()).collect();
+        println!(
+            "Pointer history, last {} of {} positions:",
+            positions.len(),
+            history.recorded()
+        );
+        println!("{}", history.sparkline());
+        println!("[{}]", positions.join(", "));
+    }
+    if let (Some(hot_cells), Some(count)) = (hot_cells, options.profile_hot_cells) {
+        println!("Most written cells:");
+        for (index, writes) in hot_cells.borrow().top(count) {
+            println!("  cell {:>5}: {} writes", index, writes);
+        }
+    }
+    if options.stats_json {
+        let stats = stats::Stats::collect(&interpreter.state, program, elapsed, exit_reason);
+        println!("{}", stats.to_json());
+    }
+
+    if let Err(e) = result {
+        if let Some(path) = &options.tape_snapshot_on_error {
+            match std::fs::write(path, interpreter.state.snapshot_json()) {
+                Ok(()) => eprintln!("Wrote tape snapshot to {}", path),
+                Err(e) => eprintln!("Error while writing tape snapshot: {}", e),
+            }
+        }
+        eprintln!("Runtime error: {}", e);
+        std::process::exit(1);
+    }
+
+    interpreter
+}
+
+/// Run the program on every chunk of input with a fresh tape, concatenating
+/// the output. Stops at the first runtime error.
+fn run_chunks<P: interpreter::Interpret>(
+    interpreter: &mut interpreter::Interpreter,
+    program: &mut P,
+    chunks: impl Iterator<Item = Vec<u8>>,
+) -> Result<(), interpreter::RuntimeError> {
+    let mut output = Vec::new();
+    let mut result = Ok(());
+    for chunk in chunks {
+        interpreter.state.reset_keep_capacity();
+        interpreter.state.input = chunk;
+        result = interpreter.interpret(program);
+        output.extend(interpreter.take_output());
+        if result.is_err() {
+            break;
+        }
+    }
+    interpreter.state.output = output;
+    result
+}
+
+/// Lines of stdin including their line endings, until EOF or a read error
+fn stdin_lines() -> impl Iterator<Item = Vec<u8>> {
+    let mut stdin = std::io::stdin().lock();
+    std::iter::from_fn(move || {
+        let mut line = Vec::new();
+        match stdin.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
+        }
+    })
+}
+
+/// Read a source file, exiting on failure
+fn read_source_or_exit(path: &str) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Error while reading file: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Write a file and mark it executable where the platform has such a bit
+fn write_executable(path: &str, contents: &str) -> std::io::Result<()> {
+    std::fs::write(path, contents)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+/// Read a binary file, exiting on failure
+fn read_bytes_or_exit(path: &str) -> Vec<u8> {
+    match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Error while reading file: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Scan source into tokens, exiting with syntax errors on failure
+fn scan_or_exit(
+    source: String,
+    fail_fast: bool,
+    comment_chars: &[char],
+    tab_width: u32,
+) -> Vec<lexer::Token> {
+    let lexer = lexer::Lexer::new(source)
+        .fail_fast(fail_fast)
+        .comment_chars(comment_chars)
+        .tab_width(tab_width);
+    match lexer.scan_tokens() {
+        Ok(tokens) => tokens,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("Syntax error at position {}: {}", error.position, error);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Tokens ready to run without a syntax tree, exiting with errors on failure
+fn direct_or_exit(tokens: &[lexer::Token]) -> direct::DirectProgram {
+    match direct::DirectProgram::new(tokens) {
+        Ok(program) => program,
+        Err(errors) => {
+            for (pos, msg) in errors {
+                eprintln!("Error at position {pos}: {msg}");
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parse tokens into a program, exiting with errors on failure
+fn parse_or_exit(tokens: Vec<lexer::Token>, fail_fast: bool) -> parser::Program {
+    match parser::Parser::new(tokens).fail_fast(fail_fast).parse() {
+        Ok(ast) => ast,
+        Err(errors) => {
+            for (pos, msg) in errors {
+                eprintln!("Error at position {pos}: {msg}");
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Read the header and tokens from an image, exiting on failure. `-` reads
+/// the PNG from stdin.
+fn read_image_or_exit(
+    path: &str,
+    offset: usize,
+    rows: Option<std::ops::Range<u32>>,
+    password: Option<&str>,
+    headerless: bool,
+) -> (Option<header::Header>, Vec<lexer::Token>) {
+    if path.ends_with(binary::EXTENSION) {
+        return match binary::read(path) {
+            Ok((header, tokens)) => (Some(header), tokens),
+            Err(e) => {
+                eprintln!("Error while reading program: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    // PNG bytes piped through stdin
+    let decoded = if path == "-" {
+        let mut bytes = Vec::new();
+        if let Err(e) = std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes) {
+            eprintln!("Error while reading stdin: {}", e);
+            std::process::exit(1);
+        }
+        img::read_bytes(&bytes, offset, rows, password, headerless)
+    } else {
+        img::read(path, offset, rows, password, headerless)
+    };
+
+    match decoded {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            eprintln!("Error while reading image: {}", e);
+            if matches!(e, img::ReadError::NoHeader) {
+                eprintln!(
+                    "Images injected before the header existed can be read with --headerless"
+                );
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parse a `KEY=VALUE` metadata argument
+fn parse_meta(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got `{}`", arg)),
+    }
+}
+
+/// Parse a `START:END` row range
+fn parse_rows(arg: &str) -> Result<std::ops::Range<u32>, String> {
+    let error = || format!("expected START:END, got `{}`", arg);
+    let (start, end) = arg.split_once(':').ok_or_else(error)?;
+    let start: u32 = start.parse().map_err(|_| error())?;
+    let end: u32 = end.parse().map_err(|_| error())?;
+    if start >= end {
+        return Err(format!("row range `{}` is empty", arg));
+    }
+    Ok(start..end)
+}
+
+/// Parse a tape size, which needs at least one cell
+fn parse_memory_size(arg: &str) -> Result<usize, String> {
+    match arg.parse() {
+        Ok(0) => Err("tape needs at least one cell".to_string()),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("expected a number of cells, got `{}`", arg)),
+    }
+}
+
+/// Compare program output against the expected string, returning a
+/// human readable diff on mismatch
+fn compare_output(expected: &str, actual: &str) -> Result<(), String> {
+    if expected == actual {
+        return Ok(());
+    }
+
+    let mismatch = expected
+        .chars()
+        .zip(actual.chars())
+        .take_while(|(e, a)| e == a)
+        .count();
+
+    Err(format!(
+        "- expected : {:?}\n+ actual   : {:?}\n  first difference at character {}",
+        expected, actual, mismatch
+    ))
+}
+
+/// Number of cells `:mem` prints when no count is given
+const REPL_MEMORY_CELLS: usize = 16;
+
+/// Handle a colon-prefixed REPL line, returning false once the REPL should exit
+fn run_meta_command(line: &str, interpreter: &mut interpreter::Interpreter) -> bool {
+    let line = line.trim();
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    let argument = argument.trim();
+
+    match command {
+        ":mem" => {
+            let count = if argument.is_empty() {
+                Ok(REPL_MEMORY_CELLS)
+            } else {
+                argument.parse::<usize>()
+            };
+            match count {
+                Ok(count) => {
+                    let memory = &interpreter.state.memory;
+                    println!("{:?}", &memory[..count.min(memory.len())]);
+                }
+                Err(_) => println!("Usage: :mem [cells]"),
+            }
+        }
+        ":ptr" => println!("{}", interpreter.state.pointer()),
+        ":reset" => {
+            interpreter.state.reset_tape();
+            println!("Tape cleared");
+        }
+        ":input" => {
+            // Everything after the command, so the text may hold spaces
+            let text = line[command.len()..].strip_prefix(' ').unwrap_or("");
+            interpreter.state.input.extend_from_slice(text.as_bytes());
+            println!("Queued {} bytes", text.len());
+        }
+        ":quit" => return false,
+        _ => println!(
+            "Unknown command {}, expected :mem, :ptr, :reset, :input or :quit",
+            command
+        ),
+    }
+    true
 }
 
 /// Run a REPL (Read, Evaluate, Print, Loop) environment
-fn run_repl(verbose: bool) {
-    use lexer::Lexer;
-    use parser::Parser;
+fn run_repl(
+    verbose: bool,
+    prompt: &str,
+    continuation_prompt: &str,
+    memory_size: usize,
+    stream: bool,
+    max_steps: Option<u64>,
+    new_lexer: impl Fn(String) -> lexer::Lexer,
+) {
     use interpreter::Interpreter;
+    use parser::Parser;
+    use rustyline::error::ReadlineError;
 
     println!(":: Brainfreeze REPL ::");
 
-    let mut reader = std::io::BufReader::new(std::io::stdin());
+    let mut cache = cache::ExecutionCache::new();
+    let mut builder = Interpreter::builder()
+        .input(vec![])
+        .memory_size(memory_size);
+    if stream {
+        builder = builder.output_stream(Rc::new(RefCell::new(std::io::stdout())));
+    }
+    if let Some(limit) = max_steps {
+        builder = builder.max_steps(limit);
+    }
+    let mut interpreter = builder.build();
+    let mut editor = match editor::Editor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Failed to start the line editor: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut buffer = String::new();
     loop {
-        let mut line = String::new();
-        // Print prompt
-        print!("> ");
-        std::io::stdout().lock().flush().unwrap();
-        // Read line
-        reader.read_line(&mut line).expect("read line");
-        // Trim line end
-        line.pop();
-
-        if line.len() == 0 {
+        // Prompt, continuing an unclosed loop from the previous line
+        let prompt = if buffer.is_empty() {
+            prompt
+        } else {
+            continuation_prompt
+        };
+        // Read line, Ctrl-D or a closed stdin ends the session
+        let line = match editor.read_line(prompt) {
+            Ok(editor::Input::Line(line)) => line,
+            // Ctrl-C drops the line, and the unclosed loop if there is one
+            Ok(editor::Input::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Ok(editor::Input::Eof) => {
+                println!();
+                println!("Bye");
+                break;
+            }
+            // Non-UTF-8 input only skips the line, other errors won't go away
+            Err(ReadlineError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData => {
+                println!();
+                println!("Failed to read line: {}", e);
+                continue;
+            }
+            Err(e) => {
+                println!();
+                println!("Failed to read line: {}", e);
+                break;
+            }
+        };
+        editor.add_history(&line);
+
+        if buffer.is_empty() && line.is_empty() {
+            continue;
+        }
+
+        if buffer.is_empty() && line.starts_with(':') {
+            if !run_meta_command(&line, &mut interpreter) {
+                break;
+            }
             continue;
         }
 
-        let lexer = Lexer::new(line);
+        buffer.push_str(&line);
+        buffer.push('\n');
+        if !new_lexer(buffer.clone()).is_balanced() {
+            continue;
+        }
+        let lexer = new_lexer(std::mem::take(&mut buffer));
         let tokens = match lexer.scan_tokens() {
             Ok(tokens) => tokens,
             Err(errors) => {
-                for (pos, msg) in errors {
-                    println!("Error at position {pos}: {msg}");
+                for error in errors {
+                    println!("Error at position {}: {}", error.position, error);
                 }
                 continue;
-            },
+            }
         };
 
         let parser = Parser::new(tokens);
         let ast = parser.parse();
 
-        let mut ast = match ast {
+        let ast = match ast {
             Ok(ast) => ast,
             Err(errors) => {
                 for (pos, msg) in errors {
@@ -169,8 +1801,33 @@ fn run_repl(verbose: bool) {
             }
         };
 
-        let mut interpreter = Interpreter::new(vec![]);
-        interpreter.interpret(&mut ast);
-        interpreter.print_state(verbose);
+        // Memory, the pointer and unread input carry over from the previous line
+        let state = &mut interpreter.state;
+        let queued = state
+            .input
+            .split_off(state.input_position.min(state.input.len()));
+        state.reset_run();
+        state.input = queued;
+        // A cached run would restore the state without writing the output
+        if stream {
+            let mut ast = ast;
+            if let Err(e) = interpreter.interpret(&mut ast) {
+                println!("Runtime error: {}", e);
+            }
+            println!();
+            if verbose {
+                interpreter.print_state(true, None);
+            }
+            continue;
+        }
+        match cache.interpret(&mut interpreter, ast) {
+            Ok(cached) => {
+                if verbose && cached {
+                    println!("Cached         :\t {} hits", cache.hits());
+                }
+            }
+            Err(e) => println!("Runtime error: {}", e),
+        }
+        interpreter.print_state(verbose, None);
     }
 }
@@ -38,27 +38,189 @@
 //! structure.
 
 use crate::{
-    interpreter::{Interpret, State},
+    interpreter::{Interpret, RuntimeError, State},
     lexer::{Position, Token, TokenKind},
 };
 
 #[derive(Debug)]
 pub struct Program {
-    pub commands: Vec<Box<dyn Command>>,
+    pub commands: Vec<Node>,
 }
+
+impl Program {
+    /// Canonical source of the program, runs are merged and formatting dropped
+    pub fn source(&self) -> String {
+        let mut source = String::new();
+        self.write_source(&mut source);
+        source
+    }
+
+    fn write_source(&self, source: &mut String) {
+        for command in self.commands.iter() {
+            command.write_source(source);
+        }
+    }
+
+    /// Number of times each loop body ran, in source order
+    pub fn loop_counts(&self) -> Vec<(Position, usize)> {
+        let mut counts = Vec::new();
+        self.collect_loop_counts(&mut counts);
+        counts
+    }
+
+    fn collect_loop_counts(&self, counts: &mut Vec<(Position, usize)>) {
+        for iteration in self.commands.iter().filter_map(Node::as_iteration) {
+            counts.push((iteration.position, iteration.iterations));
+            iteration.program.collect_loop_counts(counts);
+        }
+    }
+
+    /// Number of top level commands, a loop counts as one
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Number of operators, including the ones inside loop bodies
+    pub fn total_operators(&self) -> usize {
+        self.commands
+            .iter()
+            .map(|c| match c {
+                Node::Op(_) => 1,
+                Node::Loop(iteration) => iteration.program.total_operators(),
+            })
+            .sum()
+    }
+
+    /// Deepest loop nesting, `0` for a program without loops
+    pub fn max_depth(&self) -> usize {
+        self.commands
+            .iter()
+            .filter_map(Node::as_iteration)
+            .map(|iteration| 1 + iteration.program.max_depth())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Render the tree as a Graphviz DOT graph
+    pub fn dot(&self) -> String {
+        let mut dot = Dot::default();
+        dot.line("digraph program {");
+        dot.depth += 1;
+        let root = dot.node("program", "box");
+        self.write_dot(&mut dot, root);
+        dot.depth -= 1;
+        dot.line("}");
+        dot.output
+    }
+
+    fn write_dot(&self, dot: &mut Dot, parent: usize) {
+        for command in self.commands.iter() {
+            let node = command.write_dot(dot);
+            dot.edge(parent, node);
+        }
+    }
+}
+
+/// Graphviz DOT output being built
+#[derive(Debug, Default)]
+pub struct Dot {
+    output: String,
+    nodes: usize,
+    depth: usize,
+}
+
+impl Dot {
+    fn line(&mut self, line: &str) {
+        self.output.push_str(&"    ".repeat(self.depth));
+        self.output.push_str(line);
+        self.output.push('\n');
+    }
+
+    fn node(&mut self, label: &str, shape: &str) -> usize {
+        let id = self.nodes;
+        self.nodes += 1;
+        self.line(&format!("n{} [label={:?}, shape={}];", id, label, shape));
+        id
+    }
+
+    fn edge(&mut self, from: usize, to: usize) {
+        self.line(&format!("n{} -> n{};", from, to));
+    }
+}
+
+/// Programs are equal when they have the same canonical source
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        self.source() == other.source()
+    }
+}
+
+impl Eq for Program {}
+
+impl std::hash::Hash for Program {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.source().hash(state);
+    }
+}
+
 impl Interpret for Program {
-    fn interpret(&mut self, state: &mut State) {
+    fn interpret(&mut self, state: &mut State) -> Result<(), RuntimeError> {
         for command in self.commands.iter_mut() {
-            command.interpret(state);
+            command.interpret(state)?;
         }
+        Ok(())
     }
 }
 
 pub type ParseError = (Position, String);
 
-pub trait Command: std::fmt::Debug + Interpret {}
-
+/// A command of the program. Interpreting it is a match rather than a call
+/// through a vtable, and loop bodies are stored inline.
 #[derive(Debug)]
+pub enum Node {
+    Op(Operator),
+    Loop(Iteration),
+}
+
+impl Node {
+    fn write_source(&self, source: &mut String) {
+        match self {
+            Node::Op(operator) => operator.write_source(source),
+            Node::Loop(iteration) => iteration.write_source(source),
+        }
+    }
+
+    /// Add the command to the graph, returning its node id
+    fn write_dot(&self, dot: &mut Dot) -> usize {
+        match self {
+            Node::Op(operator) => operator.write_dot(dot),
+            Node::Loop(iteration) => iteration.write_dot(dot),
+        }
+    }
+
+    pub fn as_iteration(&self) -> Option<&Iteration> {
+        match self {
+            Node::Op(_) => None,
+            Node::Loop(iteration) => Some(iteration),
+        }
+    }
+}
+
+impl Interpret for Node {
+    #[inline]
+    fn interpret(&mut self, state: &mut State) -> Result<(), RuntimeError> {
+        match self {
+            Node::Op(operator) => operator.interpret(state),
+            Node::Loop(iteration) => iteration.interpret(state),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operator {
     Increment(usize),
     Decrement(usize),
@@ -67,56 +229,160 @@ pub enum Operator {
     PutChar,
     ReadChar,
 }
+impl Operator {
+    /// Operator for the token, `None` for loops and `EOF`
+    pub fn from_kind(kind: &TokenKind) -> Option<Self> {
+        match kind {
+            TokenKind::Increment(v) => Some(Operator::Increment(*v)),
+            TokenKind::Decrement(v) => Some(Operator::Decrement(*v)),
+            TokenKind::Right(v) => Some(Operator::Right(*v)),
+            TokenKind::Left(v) => Some(Operator::Left(*v)),
+            TokenKind::PutChar => Some(Operator::PutChar),
+            TokenKind::ReadChar => Some(Operator::ReadChar),
+            _ => None,
+        }
+    }
+}
+
 impl Interpret for Operator {
-    fn interpret(&mut self, state: &mut State) {
+    #[inline]
+    fn interpret(&mut self, state: &mut State) -> Result<(), RuntimeError> {
+        state.step()?;
         match self {
-            Operator::Increment(v) => state.memory[state.pointer] += *v as u8,
-            Operator::Decrement(v) => state.memory[state.pointer] -= *v as u8,
-            Operator::Right(v) => state.pointer += *v,
-            Operator::Left(v) => state.pointer -= *v,
+            Operator::Increment(v) => {
+                let value = state.read();
+                state.write(value.wrapping_add(*v as u8));
+            }
+            Operator::Decrement(v) => {
+                let value = state.read();
+                state.write(value.wrapping_sub(*v as u8));
+            }
+            Operator::Right(v) => state.move_right(*v)?,
+            Operator::Left(v) => state.move_left(*v)?,
             Operator::PutChar => {
-                let c = state.memory[state.pointer] as char;
-                state.output.push(c as u8);
+                let value = state.read();
+                if state.numeric_output {
+                    state.emit(format!("{} ", value).as_bytes())?;
+                } else {
+                    state.emit(&[value])?;
+                }
             }
             Operator::ReadChar => {
-                let c = state.input.pop().unwrap_or(0);
-                state.memory[state.pointer] = c;
+                let c = state.read_input();
+                if let (true, Some(c)) = (state.echo_input, c) {
+                    state.emit(&[c])?;
+                }
+                state.write(c.unwrap_or(0));
             }
         }
+        Ok(())
     }
 }
 
-impl Command for Operator {}
+impl Operator {
+    fn write_source(&self, source: &mut String) {
+        let (symbol, count) = match self {
+            Operator::Increment(v) => ('+', *v),
+            Operator::Decrement(v) => ('-', *v),
+            Operator::Right(v) => ('>', *v),
+            Operator::Left(v) => ('<', *v),
+            Operator::PutChar => ('.', 1),
+            Operator::ReadChar => (',', 1),
+        };
+        source.extend(std::iter::repeat_n(symbol, count));
+    }
+
+    fn write_dot(&self, dot: &mut Dot) -> usize {
+        let label = match self {
+            Operator::Increment(v) => format!("+ {}", v),
+            Operator::Decrement(v) => format!("- {}", v),
+            Operator::Right(v) => format!("> {}", v),
+            Operator::Left(v) => format!("< {}", v),
+            Operator::PutChar => ".".to_string(),
+            Operator::ReadChar => ",".to_string(),
+        };
+        dot.node(&label, "ellipse")
+    }
+}
 
 #[derive(Debug)]
 pub struct Iteration {
     pub program: Program,
+    /// Position of the opening `[`
+    pub position: Position,
+    /// Number of times the body ran
+    pub iterations: usize,
 }
 impl Interpret for Iteration {
-    fn interpret(&mut self, state: &mut State) {
-        while state.memory[state.pointer] != 0 {
-            self.program.interpret(state);
+    fn interpret(&mut self, state: &mut State) -> Result<(), RuntimeError> {
+        let mut cycles = 0;
+        while state.read() != 0 {
+            state.step()?;
+            self.iterations += 1;
+            cycles += 1;
+            state.check_loop_iterations(cycles)?;
+            let before = state.loop_snapshot();
+            self.program.interpret(state)?;
+            state.check_loop_progress(before)?;
         }
+        Ok(())
+    }
+}
+impl Iteration {
+    fn write_source(&self, source: &mut String) {
+        source.push('[');
+        self.program.write_source(source);
+        source.push(']');
+    }
+
+    fn write_dot(&self, dot: &mut Dot) -> usize {
+        dot.line(&format!("subgraph cluster_{} {{", dot.nodes));
+        dot.depth += 1;
+        dot.line("label=\"loop\";");
+        let node = dot.node("[ ]", "diamond");
+        self.program.write_dot(dot, node);
+        dot.depth -= 1;
+        dot.line("}");
+        node
     }
 }
-impl Command for Iteration {}
 
 #[derive(Debug)]
 pub struct Parser {
     current: usize,
     tokens: Vec<Token>,
     errors: Vec<ParseError>,
+    fail_fast: bool,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
+    pub fn new(mut tokens: Vec<Token>) -> Self {
+        // Decoded images can lose their `EOF`, the parser relies on it to stop
+        if !matches!(tokens.last().map(Token::kind), Some(TokenKind::EOF)) {
+            let position = tokens
+                .last()
+                .map_or(Position::new(0, 0), |token| *token.position());
+            tokens.push(Token::new(TokenKind::EOF, position));
+        }
+
         Self {
             current: 0,
             tokens,
             errors: Vec::new(),
+            fail_fast: false,
         }
     }
 
+    /// Stop parsing at the first error instead of collecting all of them
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    fn should_stop(&self) -> bool {
+        self.fail_fast && !self.errors.is_empty()
+    }
+
     fn peek(&self) -> &Token {
         &self.tokens[self.current]
     }
@@ -137,13 +403,13 @@ impl Parser {
     }
 
     fn push_error(&mut self, message: String) {
-        self.errors.push((self.peek().position().clone(), message));
+        self.errors.push((*self.peek().position(), message));
     }
 
     fn program(&mut self) -> Program {
-        let mut commands: Vec<Box<dyn Command>> = Vec::new();
+        let mut commands = Vec::new();
 
-        loop {
+        while !self.should_stop() {
             if let Some(operator) = self.operator() {
                 commands.push(operator);
                 continue;
@@ -160,51 +426,54 @@ impl Parser {
         Program { commands }
     }
 
-    fn operator(&mut self) -> Option<Box<dyn Command>> {
-        let operator = match self.peek().kind() {
-            TokenKind::Increment(v) => Some(Operator::Increment(*v)),
-            TokenKind::Decrement(v) => Some(Operator::Decrement(*v)),
-            TokenKind::Right(v) => Some(Operator::Right(*v)),
-            TokenKind::Left(v) => Some(Operator::Left(*v)),
-            TokenKind::PutChar => Some(Operator::PutChar),
-            TokenKind::ReadChar => Some(Operator::ReadChar),
-            _ => None,
-        };
+    fn operator(&mut self) -> Option<Node> {
+        let operator = Operator::from_kind(self.peek().kind());
 
         if let Some(operator) = operator {
             self.advance();
-            let boxed = Box::new(operator);
-            Some(boxed)
+            Some(Node::Op(operator))
         } else {
             None
         }
     }
 
-    fn iteration(&mut self) -> Option<Box<dyn Command>> {
+    fn iteration(&mut self) -> Option<Node> {
         if !matches!(self.peek().kind(), TokenKind::LoopStart) {
             return None;
         }
-        self.advance();
+        let position = *self.advance().position();
 
         let program = self.program();
-
-        if !matches!(self.peek().kind(), TokenKind::LoopEnd) {
-            self.push_error("Expected ']'".to_string());
+        if self.should_stop() {
             return None;
         }
-        self.advance();
 
-        Some(Box::new(Iteration { program }))
+        // Without a `]` the rest of the program is the body. The loop is kept
+        // so enclosing loops see it and only this one error is reported.
+        if matches!(self.peek().kind(), TokenKind::LoopEnd) {
+            self.advance();
+        } else {
+            self.push_error(format!("Unclosed '[' opened at {}", position));
+        }
+
+        Some(Node::Loop(Iteration {
+            program,
+            position,
+            iterations: 0,
+        }))
     }
 
     pub fn parse(mut self) -> Result<Program, Vec<ParseError>> {
-        let program = self.program();
-        while !self.at_end() {
-            self.push_error(format!("Unexpected token {}", self.peek().kind()));
+        let mut program = self.program();
+        // Only a `]` stops the top level early, there is no `[` open for it.
+        // Parsing carries on after it to report every stray one.
+        while !self.at_end() && !self.should_stop() {
+            self.push_error("Unmatched ']', there is no '[' to close".to_string());
             self.advance();
+            program.commands.extend(self.program().commands);
         }
 
-        if self.errors.len() == 0 {
+        if self.errors.is_empty() {
             Ok(program)
         } else {
             Err(self.errors)
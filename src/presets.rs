@@ -0,0 +1,42 @@
+//! # Presets
+//! Well-known programs bundled with `brainfreeze`, so performance can be
+//! checked without hunting for sources.
+
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub source: &'static str,
+    /// Steps the preset may take before the run is cut short as a runaway
+    pub max_steps: Option<u64>,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "hello",
+        description: "Prints \"Hello, World!\"",
+        source: include_str!("../hello-world.bfk"),
+        max_steps: None,
+    },
+    Preset {
+        name: "primes",
+        description: "Prints the primes below 50 by trial division",
+        source: include_str!("../presets/primes.bf"),
+        max_steps: None,
+    },
+    Preset {
+        name: "clear",
+        description: "Clears cells with [-] over and over",
+        source: include_str!("../presets/clear.bf"),
+        max_steps: None,
+    },
+    Preset {
+        name: "mandelbrot",
+        description: "Draws the Mandelbrot set in ASCII with fixed point arithmetic",
+        source: include_str!("../presets/mandelbrot.bf"),
+        max_steps: Some(100_000_000),
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
@@ -0,0 +1,125 @@
+//! # Statistics
+//! Execution statistics gathered after a run, serialized as JSON for CI
+//! and dashboards.
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    interpreter::{Access, MemoryLogger, State},
+    lexer::Position,
+    parser::Program,
+};
+
+/// Programs that can report on their loops after a run
+pub trait Profile {
+    /// Iteration count of every loop, by the position of its `[`
+    fn loop_counts(&self) -> Vec<(Position, usize)>;
+
+    /// Deepest loop nesting, `0` for a program without loops
+    fn max_depth(&self) -> usize;
+}
+
+impl Profile for Program {
+    fn loop_counts(&self) -> Vec<(Position, usize)> {
+        Program::loop_counts(self)
+    }
+
+    fn max_depth(&self) -> usize {
+        Program::max_depth(self)
+    }
+}
+
+/// Tallies the writes to every cell through the memory hook
+#[derive(Debug, Default)]
+pub struct HotCells {
+    writes: HashMap<usize, u64>,
+}
+
+impl MemoryLogger for HotCells {
+    fn log(&mut self, access: Access, index: usize, _value: u8) {
+        if let Access::Write = access {
+            *self.writes.entry(index).or_default() += 1;
+        }
+    }
+}
+
+impl HotCells {
+    /// The `count` most written cells with their write counts, ties go to
+    /// the lower cell
+    pub fn top(&self, count: usize) -> Vec<(usize, u64)> {
+        let mut cells: Vec<(usize, u64)> = self.writes.iter().map(|(&i, &n)| (i, n)).collect();
+        cells.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        cells.truncate(count);
+        cells
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExitReason {
+    Completed,
+    RuntimeError,
+    StepLimit,
+}
+
+impl ExitReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ExitReason::Completed => "completed",
+            ExitReason::RuntimeError => "runtime_error",
+            ExitReason::StepLimit => "step_limit",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    pub steps: u64,
+    pub elapsed: Duration,
+    pub exit_reason: ExitReason,
+    pub output_length: usize,
+    pub max_depth: usize,
+    pub loops: Vec<(Position, usize)>,
+}
+
+impl Stats {
+    pub fn collect(
+        state: &State,
+        program: &dyn Profile,
+        elapsed: Duration,
+        exit_reason: ExitReason,
+    ) -> Self {
+        Self {
+            steps: state.steps,
+            elapsed,
+            exit_reason,
+            output_length: state.output.len(),
+            max_depth: program.max_depth(),
+            loops: program.loop_counts(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let loops: Vec<String> = self
+            .loops
+            .iter()
+            .map(|(position, iterations)| {
+                format!(
+                    "{{\"line\":{},\"offset\":{},\"iterations\":{}}}",
+                    position.line_number() + 1,
+                    position.offset() + 1,
+                    iterations
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"steps\":{},\"elapsed_ms\":{:.3},\"exit_reason\":\"{}\",\"output_length\":{},\"max_depth\":{},\"loops\":[{}]}}",
+            self.steps,
+            self.elapsed.as_secs_f64() * 1000.0,
+            self.exit_reason.as_str(),
+            self.output_length,
+            self.max_depth,
+            loops.join(",")
+        )
+    }
+}